@@ -0,0 +1,6274 @@
+//! Generates systemd units from YAML, JSON or TOML definitions. `load` reads a
+//! definitions file and `generate` writes the units it describes.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::BufReader,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+mod toml;
+use toml::{parse_toml, parse_toml_located, to_toml};
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(v) => vec![v],
+            OneOrMany::Many(v) => v,
+        }
+    }
+}
+
+fn default_template_deps() -> Vec<String> {
+    vec![]
+}
+
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct TemplateUnit {
+    pub description: Option<String>,
+    #[serde(default = "default_template_deps")]
+    pub requires: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub after: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub before: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub wants: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub conflicts: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub upholds: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub on_failure: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub on_success: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub documentation: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub propagates_reload_to: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub reload_propagated_from: Vec<String>,
+    #[serde(default = "default_template_deps")]
+    pub requires_mounts_for: Vec<String>,
+    pub start_limit_action: Option<UnitAction>,
+    pub start_limit_burst: Option<u32>,
+    pub start_limit_interval_sec: Option<TimeSpan>,
+    pub default_dependencies: Option<bool>,
+    pub stop_when_unneeded: Option<bool>,
+    pub refuse_manual_start: Option<bool>,
+    pub refuse_manual_stop: Option<bool>,
+    pub job_timeout_sec: Option<TimeSpan>,
+    pub job_timeout_action: Option<UnitAction>,
+    pub success_action: Option<UnitAction>,
+    pub failure_action: Option<UnitAction>,
+    pub ignore_on_isolate: Option<bool>,
+    pub collect_mode: Option<CollectMode>,
+    pub conditions: Option<BTreeMap<String, OneOrMany<BoolOrString>>>,
+    pub asserts: Option<BTreeMap<String, OneOrMany<BoolOrString>>>,
+}
+
+fn default_inherit_requires() -> bool {
+    true
+}
+
+fn default_inherit_after() -> bool {
+    true
+}
+
+fn default_inherit_before() -> bool {
+    true
+}
+
+fn default_inherit_wants() -> bool {
+    true
+}
+
+fn default_inherit_conflicts() -> bool {
+    true
+}
+
+fn default_inherit_upholds() -> bool {
+    true
+}
+
+fn default_inherit_on_failure() -> bool {
+    true
+}
+
+fn default_inherit_on_success() -> bool {
+    true
+}
+
+fn default_inherit_documentation() -> bool {
+    true
+}
+
+fn default_inherit_propagates_reload_to() -> bool {
+    true
+}
+
+fn default_inherit_reload_propagated_from() -> bool {
+    true
+}
+
+fn default_inherit_requires_mounts_for() -> bool {
+    true
+}
+
+fn default_dedupe() -> bool {
+    true
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct InstanceUnit {
+    pub name: String,
+    /// Required unless the unit is written as a drop-in
+    pub description: Option<String>,
+    pub requires: Option<Vec<String>>,
+    pub after: Option<Vec<String>>,
+    pub before: Option<Vec<String>>,
+    pub wants: Option<Vec<String>>,
+    pub conflicts: Option<Vec<String>>,
+    pub upholds: Option<Vec<String>>,
+    pub on_failure: Option<Vec<String>>,
+    pub on_success: Option<Vec<String>>,
+    pub documentation: Option<Vec<String>>,
+    pub propagates_reload_to: Option<Vec<String>>,
+    pub reload_propagated_from: Option<Vec<String>>,
+    #[serde(default = "default_inherit_requires")]
+    pub inherit_requires: bool,
+    #[serde(default = "default_inherit_after")]
+    pub inherit_after: bool,
+    #[serde(default = "default_inherit_before")]
+    pub inherit_before: bool,
+    #[serde(default = "default_inherit_wants")]
+    pub inherit_wants: bool,
+    #[serde(default = "default_inherit_conflicts")]
+    pub inherit_conflicts: bool,
+    #[serde(default = "default_inherit_upholds")]
+    pub inherit_upholds: bool,
+    #[serde(default = "default_inherit_on_failure")]
+    pub inherit_on_failure: bool,
+    #[serde(default = "default_inherit_on_success")]
+    pub inherit_on_success: bool,
+    #[serde(default = "default_inherit_documentation")]
+    pub inherit_documentation: bool,
+    #[serde(default = "default_inherit_propagates_reload_to")]
+    pub inherit_propagates_reload_to: bool,
+    #[serde(default = "default_inherit_reload_propagated_from")]
+    pub inherit_reload_propagated_from: bool,
+    pub requires_mounts_for: Option<Vec<String>>,
+    #[serde(default = "default_inherit_requires_mounts_for")]
+    pub inherit_requires_mounts_for: bool,
+    pub part_of: Option<Vec<String>>,
+    pub merge: Option<BTreeMap<String, MergeStrategy>>,
+    #[serde(default = "default_dedupe")]
+    pub dedupe: bool,
+    pub start_limit_action: Option<UnitAction>,
+    pub start_limit_burst: Option<u32>,
+    pub start_limit_interval_sec: Option<TimeSpan>,
+    pub default_dependencies: Option<bool>,
+    pub stop_when_unneeded: Option<bool>,
+    pub refuse_manual_start: Option<bool>,
+    pub refuse_manual_stop: Option<bool>,
+    pub job_timeout_sec: Option<TimeSpan>,
+    pub job_timeout_action: Option<UnitAction>,
+    pub success_action: Option<UnitAction>,
+    pub failure_action: Option<UnitAction>,
+    pub ignore_on_isolate: Option<bool>,
+    pub collect_mode: Option<CollectMode>,
+    pub conditions: Option<BTreeMap<String, OneOrMany<BoolOrString>>>,
+    pub asserts: Option<BTreeMap<String, OneOrMany<BoolOrString>>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnitAction {
+    None,
+    Reboot,
+    RebootForce,
+    RebootImmediate,
+    Poweroff,
+    PoweroffForce,
+    PoweroffImmediate,
+    Exit,
+    ExitForce,
+    SoftReboot,
+    SoftRebootForce,
+    Kexec,
+    KexecForce,
+    Halt,
+    HaltForce,
+    HaltImmediate,
+}
+
+impl core::fmt::Display for UnitAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self
+            .serialize(serde_yaml::value::Serializer)
+            .map_err(|_| std::fmt::Error)?;
+        f.write_str(value.as_str().ok_or(std::fmt::Error)?)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollectMode {
+    Inactive,
+    InactiveOrFailed,
+}
+
+impl core::fmt::Display for CollectMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CollectMode::Inactive => "inactive",
+            CollectMode::InactiveOrFailed => "inactive-or-failed",
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    No,
+    Always,
+    OnSuccess,
+    OnFailure,
+    OnAbnormal,
+    OnWatchdog,
+    OnAbort,
+}
+
+impl core::fmt::Display for Restart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Restart::No => "no",
+            Restart::Always => "always",
+            Restart::OnSuccess => "on-success",
+            Restart::OnFailure => "on-failure",
+            Restart::OnAbnormal => "on-abnormal",
+            Restart::OnWatchdog => "on-watchdog",
+            Restart::OnAbort => "on-abort",
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RemainAfterExit {
+    No,
+    Yes,
+}
+
+impl core::fmt::Display for RemainAfterExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RemainAfterExit::No => "no",
+            RemainAfterExit::Yes => "yes",
+        })
+    }
+}
+
+fn default_remain_after_exit() -> Option<RemainAfterExit> {
+    Some(RemainAfterExit::No)
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceType {
+    Simple,
+    Exec,
+    OneShot,
+    Forking,
+    Notify,
+    #[serde(rename = "notify-reload")]
+    NotifyReload,
+    DBus,
+    Idle,
+}
+
+impl core::fmt::Display for ServiceType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self
+            .serialize(serde_yaml::value::Serializer)
+            .map_err(|_| std::fmt::Error)?;
+        f.write_str(value.as_str().ok_or(std::fmt::Error)?)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyAccess {
+    None,
+    Main,
+    Exec,
+    All,
+}
+
+impl core::fmt::Display for NotifyAccess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NotifyAccess::None => "none",
+            NotifyAccess::Main => "main",
+            NotifyAccess::Exec => "exec",
+            NotifyAccess::All => "all",
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum KillMode {
+    ControlGroup,
+    Mixed,
+    Process,
+    None,
+}
+
+impl core::fmt::Display for KillMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            KillMode::ControlGroup => "control-group",
+            KillMode::Mixed => "mixed",
+            KillMode::Process => "process",
+            KillMode::None => "none",
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum OomPolicy {
+    Continue,
+    Stop,
+    Kill,
+}
+
+impl core::fmt::Display for OomPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OomPolicy::Continue => "continue",
+            OomPolicy::Stop => "stop",
+            OomPolicy::Kill => "kill",
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuSchedulingPolicy {
+    Other,
+    Batch,
+    Idle,
+    Fifo,
+    Rr,
+}
+
+impl core::fmt::Display for CpuSchedulingPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CpuSchedulingPolicy::Other => "other",
+            CpuSchedulingPolicy::Batch => "batch",
+            CpuSchedulingPolicy::Idle => "idle",
+            CpuSchedulingPolicy::Fifo => "fifo",
+            CpuSchedulingPolicy::Rr => "rr",
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoSchedulingClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl core::fmt::Display for IoSchedulingClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IoSchedulingClass::Realtime => "realtime",
+            IoSchedulingClass::BestEffort => "best-effort",
+            IoSchedulingClass::Idle => "idle",
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Delegate {
+    Bool(bool),
+    Controllers(Vec<String>),
+}
+
+const DELEGATE_CONTROLLERS: &[&str] = &[
+    "cpu",
+    "cpuacct",
+    "cpuset",
+    "io",
+    "blkio",
+    "memory",
+    "devices",
+    "pids",
+    "bpf-firewall",
+    "bpf-devices",
+];
+
+impl core::fmt::Display for Delegate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Delegate::Bool(v) => f.write_str(&v.to_string()),
+            Delegate::Controllers(v) => f.write_str(&v.join(" ")),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum RestrictNamespaces {
+    Bool(bool),
+    Namespaces(Vec<String>),
+}
+
+const NAMESPACE_TYPES: &[&str] = &["cgroup", "ipc", "net", "mnt", "pid", "user", "uts"];
+
+fn default_service_type() -> Option<ServiceType> {
+    None
+}
+
+/// A command whose words are passed literally: quotes, `%` specifiers and, in
+/// `Args`, `$` variables are escaped. Use the plain string form to expand them.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct StructuredCommand {
+    #[serde(alias = "cmd")]
+    pub cmd: String,
+    #[serde(default = "default_template_deps", alias = "args")]
+    pub args: Vec<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Command {
+    Line(String),
+    Structured(StructuredCommand),
+}
+
+fn quote_arg(arg: &str) -> String {
+    let needs_quoting = arg.is_empty()
+        || arg
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\' || c == ';');
+    if !needs_quoting {
+        return arg.into();
+    }
+    quote(arg)
+}
+
+fn quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+/// Keeps systemd from expanding specifiers and, in arguments, environment
+/// variables inside a structured command.
+fn escape_arg(arg: &str, variables: bool) -> String {
+    let escaped = arg.replace('%', "%%");
+    match variables {
+        true => escaped.replace('$', "$$"),
+        false => escaped,
+    }
+}
+
+impl core::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Line(v) => f.write_str(v),
+            Command::Structured(v) => {
+                let mut words = vec![quote_arg(&escape_arg(&v.cmd, false))];
+                words.extend(v.args.iter().map(|a| quote_arg(&escape_arg(a, true))));
+                f.write_str(&words.join(" "))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum NumberOrString {
+    Number(u64),
+    Text(String),
+}
+
+impl core::fmt::Display for NumberOrString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NumberOrString::Number(v) => f.write_str(&v.to_string()),
+            NumberOrString::Text(v) => f.write_str(v),
+        }
+    }
+}
+
+type TimeSpan = NumberOrString;
+
+const TIME_SPAN_UNITS: &[&str] = &[
+    "", "usec", "us", "µs", "msec", "ms", "seconds", "second", "sec", "s", "minutes", "minute",
+    "min", "m", "hours", "hour", "hr", "h", "days", "day", "d", "weeks", "week", "w", "months",
+    "month", "M", "years", "year", "y",
+];
+
+fn is_valid_time_span(value: &TimeSpan) -> bool {
+    let v = match value {
+        NumberOrString::Number(_) => return true,
+        NumberOrString::Text(v) if v == "infinity" => return true,
+        NumberOrString::Text(v) => v.trim(),
+    };
+    if v.is_empty() {
+        return false;
+    }
+
+    let mut rest = v;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        if number_len == 0 {
+            return false;
+        }
+        rest = rest[number_len..].trim_start();
+        let unit_len = rest
+            .find(|c: char| !c.is_alphabetic())
+            .unwrap_or(rest.len());
+        if !TIME_SPAN_UNITS.contains(&&rest[..unit_len]) {
+            return false;
+        }
+        rest = rest[unit_len..].trim_start();
+    }
+    true
+}
+
+fn is_valid_exit_status(value: &NumberOrString) -> bool {
+    match value {
+        NumberOrString::Number(v) => *v <= 255,
+        NumberOrString::Text(v) => v.parse::<u8>().is_ok() || is_valid_signal(v),
+    }
+}
+
+fn is_valid_signal(value: &str) -> bool {
+    let name = value.strip_prefix("SIG").unwrap_or(value);
+    // Linux has 30 real-time signals past SIGRTMIN
+    let realtime =
+        |n: &str| n.bytes().all(|c| c.is_ascii_digit()) && n.parse::<u8>().is_ok_and(|n| n <= 30);
+    if let Some(n) = name.strip_prefix("RTMIN") {
+        return n.strip_prefix('+').map_or(n.is_empty(), realtime);
+    }
+    if let Some(n) = name.strip_prefix("RTMAX") {
+        return n.strip_prefix('-').map_or(n.is_empty(), realtime);
+    }
+    [
+        "HUP", "INT", "QUIT", "ILL", "TRAP", "ABRT", "IOT", "BUS", "FPE", "KILL", "USR1", "SEGV",
+        "USR2", "PIPE", "ALRM", "TERM", "STKFLT", "CHLD", "CONT", "STOP", "TSTP", "TTIN", "TTOU",
+        "URG", "XCPU", "XFSZ", "VTALRM", "PROF", "WINCH", "IO", "POLL", "PWR", "SYS",
+    ]
+    .contains(&name)
+}
+
+fn exit_status_list(statuses: &[NumberOrString]) -> String {
+    let statuses: Vec<String> = statuses.iter().map(|s| s.to_string()).collect();
+    statuses.join(" ")
+}
+
+fn is_valid_output(value: &str) -> bool {
+    match value.split_once(':') {
+        Some(("file" | "append" | "truncate", path)) => path.starts_with('/'),
+        Some(("fd", name)) => !name.is_empty(),
+        Some(_) => false,
+        None => [
+            "inherit",
+            "null",
+            "tty",
+            "journal",
+            "kmsg",
+            "journal+console",
+            "kmsg+console",
+            "socket",
+            "fd",
+        ]
+        .contains(&value),
+    }
+}
+
+fn is_valid_input(value: &str) -> bool {
+    match value.split_once(':') {
+        Some(("file", path)) => path.starts_with('/'),
+        Some(("fd", name)) => !name.is_empty(),
+        Some(_) => false,
+        None => [
+            "null",
+            "tty",
+            "tty-force",
+            "tty-fail",
+            "data",
+            "socket",
+            "fd",
+        ]
+        .contains(&value),
+    }
+}
+
+fn is_valid_limit(value: &NumberOrString) -> bool {
+    match value {
+        NumberOrString::Number(_) => true,
+        NumberOrString::Text(v) => {
+            let parts: Vec<&str> = v.split(':').collect();
+            parts.len() <= 2
+                && parts
+                    .iter()
+                    .all(|p| *p == "infinity" || p.starts_with(|c: char| c.is_ascii_digit()))
+        }
+    }
+}
+
+fn is_valid_memory(value: &NumberOrString) -> bool {
+    match value {
+        NumberOrString::Number(_) => true,
+        NumberOrString::Text(v) if v == "infinity" => true,
+        NumberOrString::Text(v) => match v.strip_suffix('%') {
+            Some(pct) => pct.parse::<f64>().is_ok_and(|p| (0.0..=100.0).contains(&p)),
+            None => {
+                let digits = v.trim_end_matches(['K', 'M', 'G', 'T']);
+                v.len() - digits.len() <= 1
+                    && !digits.is_empty()
+                    && digits.chars().all(|c| c.is_ascii_digit())
+            }
+        },
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum BoolOrString {
+    Bool(bool),
+    Text(String),
+}
+
+impl core::fmt::Display for BoolOrString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoolOrString::Bool(v) => f.write_str(&v.to_string()),
+            BoolOrString::Text(v) => f.write_str(v),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DevicePolicy {
+    Auto,
+    Closed,
+    Strict,
+}
+
+impl core::fmt::Display for DevicePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DevicePolicy::Auto => "auto",
+            DevicePolicy::Closed => "closed",
+            DevicePolicy::Strict => "strict",
+        })
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Sandbox {
+    pub no_new_privileges: Option<bool>,
+    pub bind_paths: Option<Vec<String>>,
+    pub bind_read_only_paths: Option<Vec<String>>,
+    pub device_allow: Option<Vec<String>>,
+    pub device_policy: Option<DevicePolicy>,
+    pub inaccessible_paths: Option<Vec<String>>,
+    #[serde(rename = "IPAddressAllow")]
+    pub ip_address_allow: Option<Vec<String>>,
+    #[serde(rename = "IPAddressDeny")]
+    pub ip_address_deny: Option<Vec<String>>,
+    pub lock_personality: Option<bool>,
+    pub memory_deny_write_execute: Option<bool>,
+    #[serde(rename = "MountAPIVFS")]
+    pub mount_api_vfs: Option<bool>,
+    pub private_network: Option<bool>,
+    pub private_devices: Option<bool>,
+    pub private_tmp: Option<bool>,
+    pub protect_clock: Option<bool>,
+    pub protect_control_groups: Option<bool>,
+    pub protect_home: Option<BoolOrString>,
+    pub protect_hostname: Option<bool>,
+    pub protect_kernel_logs: Option<bool>,
+    pub protect_kernel_modules: Option<bool>,
+    pub protect_kernel_tunables: Option<bool>,
+    pub protect_system: Option<BoolOrString>,
+    pub read_only_paths: Option<Vec<String>>,
+    pub read_write_paths: Option<Vec<String>>,
+    pub restrict_address_families: Option<Vec<String>>,
+    pub restrict_namespaces: Option<RestrictNamespaces>,
+    pub restrict_realtime: Option<bool>,
+    #[serde(rename = "RestrictSUIDSGID")]
+    pub restrict_suid_sgid: Option<bool>,
+    pub system_call_architectures: Option<Vec<String>>,
+    pub system_call_error_number: Option<String>,
+    pub system_call_filter: Option<Vec<String>>,
+    pub temporary_file_system: Option<Vec<String>>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Service {
+    pub ambient_capabilities: Option<Vec<String>>,
+    pub bus_name: Option<String>,
+    pub capability_bounding_set: Option<Vec<String>>,
+    pub cache_directory: Option<OneOrMany<String>>,
+    pub configuration_directory: Option<OneOrMany<String>>,
+    pub coredump_filter: Option<OneOrMany<String>>,
+    #[serde(rename = "CPUAffinity")]
+    pub cpu_affinity: Option<Vec<NumberOrString>>,
+    #[serde(rename = "CPUQuota")]
+    pub cpu_quota: Option<String>,
+    #[serde(rename = "CPUSchedulingPolicy")]
+    pub cpu_scheduling_policy: Option<CpuSchedulingPolicy>,
+    #[serde(rename = "CPUSchedulingPriority")]
+    pub cpu_scheduling_priority: Option<u8>,
+    #[serde(rename = "CPUWeight")]
+    pub cpu_weight: Option<u32>,
+    #[serde(rename = "CPUAccounting")]
+    pub cpu_accounting: Option<bool>,
+    pub delegate: Option<Delegate>,
+    pub dynamic_user: Option<bool>,
+    pub environment: Option<BTreeMap<String, String>>,
+    pub environment_file: Option<OneOrMany<String>>,
+    pub exec_start_pre: Option<OneOrMany<Command>>,
+    pub exec_start: Option<OneOrMany<Command>>,
+    pub exec_start_post: Option<Command>,
+    pub exec_reload: Option<Command>,
+    pub exec_stop: Option<Command>,
+    pub exec_stop_post: Option<Command>,
+    pub file_descriptor_store_max: Option<u32>,
+    pub final_kill_signal: Option<String>,
+    pub group: Option<String>,
+    pub import_credential: Option<Vec<String>>,
+    #[serde(rename = "IOAccounting")]
+    pub io_accounting: Option<bool>,
+    #[serde(rename = "IOReadBandwidthMax")]
+    pub io_read_bandwidth_max: Option<BTreeMap<String, NumberOrString>>,
+    #[serde(rename = "IOSchedulingClass")]
+    pub io_scheduling_class: Option<IoSchedulingClass>,
+    #[serde(rename = "IOSchedulingPriority")]
+    pub io_scheduling_priority: Option<u8>,
+    #[serde(rename = "IOWeight")]
+    pub io_weight: Option<u32>,
+    #[serde(rename = "IOWriteBandwidthMax")]
+    pub io_write_bandwidth_max: Option<BTreeMap<String, NumberOrString>>,
+    #[serde(rename = "IPAccounting")]
+    pub ip_accounting: Option<bool>,
+    pub kill_mode: Option<KillMode>,
+    pub kill_signal: Option<String>,
+    pub limits: Option<BTreeMap<String, NumberOrString>>,
+    pub load_credential: Option<BTreeMap<String, String>>,
+    pub log_rate_limit_burst: Option<u32>,
+    pub log_rate_limit_interval_sec: Option<TimeSpan>,
+    pub logs_directory: Option<OneOrMany<String>>,
+    pub memory_accounting: Option<bool>,
+    pub memory_high: Option<NumberOrString>,
+    pub memory_low: Option<NumberOrString>,
+    pub memory_max: Option<NumberOrString>,
+    pub memory_swap_max: Option<NumberOrString>,
+    pub nice: Option<i8>,
+    pub notify_access: Option<NotifyAccess>,
+    #[serde(rename = "OOMPolicy")]
+    pub oom_policy: Option<OomPolicy>,
+    #[serde(rename = "OOMScoreAdjust")]
+    pub oom_score_adjust: Option<i32>,
+    #[serde(rename = "PAMName")]
+    pub pam_name: Option<String>,
+    #[serde(rename = "PIDFile")]
+    pub pid_file: Option<String>,
+    pub pass_environment: Option<Vec<String>>,
+    pub remain_after_exit: Option<RemainAfterExit>,
+    pub restart: Option<Restart>,
+    pub restart_force_exit_status: Option<Vec<NumberOrString>>,
+    pub restart_max_delay_sec: Option<TimeSpan>,
+    pub restart_prevent_exit_status: Option<Vec<NumberOrString>>,
+    pub restart_sec: Option<TimeSpan>,
+    pub restart_steps: Option<u32>,
+    pub runtime_directory: Option<OneOrMany<String>>,
+    pub runtime_max_sec: Option<TimeSpan>,
+    #[serde(rename = "SendSIGHUP")]
+    pub send_sighup: Option<bool>,
+    #[serde(rename = "SendSIGKILL")]
+    pub send_sigkill: Option<bool>,
+    pub set_credential: Option<BTreeMap<String, String>>,
+    pub slice: Option<String>,
+    pub sockets: Option<Vec<String>>,
+    pub standard_error: Option<String>,
+    pub standard_input: Option<String>,
+    pub standard_output: Option<String>,
+    pub state_directory: Option<OneOrMany<String>>,
+    pub success_exit_status: Option<Vec<NumberOrString>>,
+    pub supplementary_groups: Option<Vec<String>>,
+    pub syslog_identifier: Option<String>,
+    pub tasks_max: Option<NumberOrString>,
+    pub timeout_abort_sec: Option<TimeSpan>,
+    pub timeout_start_sec: Option<TimeSpan>,
+    pub timeout_stop_sec: Option<TimeSpan>,
+    #[serde(rename = "TTYPath")]
+    pub tty_path: Option<String>,
+    #[serde(rename = "TTYReset")]
+    pub tty_reset: Option<bool>,
+    #[serde(default = "default_service_type", rename = "Type")]
+    pub service_type: Option<ServiceType>,
+    #[serde(rename = "UMask")]
+    pub umask: Option<String>,
+    pub unset_environment: Option<Vec<String>>,
+    pub user: Option<String>,
+    pub watchdog_sec: Option<TimeSpan>,
+    pub watchdog_signal: Option<String>,
+    pub working_directory: Option<String>,
+    pub sandbox: Option<Sandbox>,
+    /// Fields an instance set to an explicit null, cleared from the template
+    #[serde(skip)]
+    pub unset: Vec<String>,
+}
+
+fn default_instance_service() -> Option<Service> {
+    None
+}
+
+fn deserialize_instance_service<'de, D>(deserializer: D) -> Result<Option<Service>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let mut value = serde_yaml::Value::deserialize(deserializer)?;
+    let mut unset = vec![];
+    // unknown fields are left in place for deny_unknown_fields to report
+    let fields = serde_yaml::to_value(Service::default()).map_err(serde::de::Error::custom)?;
+    if let serde_yaml::Value::Mapping(m) = &mut value {
+        for (k, v) in m.iter() {
+            if let (serde_yaml::Value::String(k), serde_yaml::Value::Null) = (k, v) {
+                if fields.get(k.as_str()).is_some() {
+                    unset.push(k.clone());
+                }
+            }
+        }
+        for k in &unset {
+            m.remove(k.as_str());
+        }
+    }
+    let service: Option<Service> =
+        serde_yaml::from_value(value).map_err(serde::de::Error::custom)?;
+    Ok(service.map(|s| Service { unset, ..s }))
+}
+
+fn clear_service_fields(service: Service, keys: &[String]) -> Result<Service, String> {
+    let mut value = serde_yaml::to_value(service).map_err(|e| e.to_string())?;
+    if let serde_yaml::Value::Mapping(m) = &mut value {
+        for k in keys {
+            m.remove(k.as_str());
+        }
+    }
+    serde_yaml::from_value(value).map_err(|e| e.to_string())
+}
+
+fn default_wanted_by() -> String {
+    "default.target".into()
+}
+
+fn default_install_wanted_by() -> OneOrMany<String> {
+    OneOrMany::One(default_wanted_by())
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Install {
+    pub wanted_by: Option<OneOrMany<String>>,
+    pub required_by: Option<Vec<String>>,
+    pub also: Option<Vec<String>>,
+    pub alias: Option<Vec<String>>,
+    pub default_instance: Option<String>,
+}
+
+fn merge_lists<T>(template: Option<Vec<T>>, instance: Option<Vec<T>>) -> Option<Vec<T>> {
+    match (template, instance) {
+        (Some(mut t), Some(i)) => {
+            t.extend(i);
+            Some(t)
+        }
+        (t, i) => i.or(t),
+    }
+}
+
+fn merge_install(instance_install: Install, template_install: Install) -> Install {
+    Install {
+        wanted_by: merge_lists(
+            template_install.wanted_by.map(OneOrMany::into_vec),
+            instance_install.wanted_by.map(OneOrMany::into_vec),
+        )
+        .map(OneOrMany::Many),
+        required_by: merge_lists(template_install.required_by, instance_install.required_by),
+        also: merge_lists(template_install.also, instance_install.also),
+        alias: merge_lists(template_install.alias, instance_install.alias),
+        default_instance: instance_install
+            .default_instance
+            .or(template_install.default_instance),
+    }
+}
+
+fn default_install() -> Install {
+    Install {
+        wanted_by: None,
+        required_by: None,
+        also: None,
+        alias: None,
+        default_instance: None,
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Timer {
+    pub on_calendar: Option<String>,
+    pub on_boot_sec: Option<String>,
+    pub on_unit_active_sec: Option<String>,
+    pub persistent: Option<bool>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Socket {
+    pub listen_stream: Option<NumberOrString>,
+    pub listen_datagram: Option<NumberOrString>,
+    pub socket_user: Option<String>,
+    pub socket_mode: Option<String>,
+    pub accept: Option<bool>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Nspawn {
+    pub boot: Option<bool>,
+    pub bind: Option<Vec<String>>,
+    pub bind_read_only: Option<Vec<String>>,
+    pub private_network: Option<bool>,
+    pub virtual_ethernet: Option<bool>,
+    pub bridge: Option<String>,
+    pub port: Option<Vec<String>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Directory {
+    pub path: String,
+    pub mode: Option<String>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+fn default_template_unit() -> TemplateUnit {
+    TemplateUnit::default()
+}
+
+fn default_template_service() -> Service {
+    Service::default()
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct TemplateServiceDef {
+    pub extends: Option<String>,
+    pub vars: Option<BTreeMap<String, String>>,
+    #[serde(default = "default_template_unit")]
+    pub unit: TemplateUnit,
+    #[serde(default = "default_template_service")]
+    pub service: Service,
+    pub install: Option<Install>,
+    pub timer: Option<Timer>,
+    pub socket: Option<Socket>,
+    pub nspawn: Option<Nspawn>,
+    pub directories: Option<Vec<Directory>>,
+}
+
+fn default_drop_in() -> bool {
+    false
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_inherit_install() -> bool {
+    false
+}
+
+fn default_inherit_service() -> bool {
+    true
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct InstanceServiceDef {
+    pub vars: Option<BTreeMap<String, String>>,
+    pub unit: InstanceUnit,
+    #[serde(
+        default = "default_instance_service",
+        deserialize_with = "deserialize_instance_service"
+    )]
+    pub service: Option<Service>,
+    pub install: Option<Install>,
+    pub timer: Option<Timer>,
+    pub socket: Option<Socket>,
+    pub nspawn: Option<Nspawn>,
+    pub directories: Option<Vec<Directory>>,
+    #[serde(default = "default_drop_in")]
+    pub drop_in: bool,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_inherit_install")]
+    pub inherit_install: bool,
+    #[serde(default = "default_inherit_service")]
+    pub inherit_service: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Target {
+    pub name: String,
+    pub description: String,
+    #[serde(default = "default_wanted_by")]
+    pub wanted_by: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    System,
+    User,
+}
+
+fn default_sysusers() -> bool {
+    false
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TemplatesAndInstances {
+    pub template: TemplateServiceDef,
+    pub instances: Vec<InstanceServiceDef>,
+    pub target: Option<Target>,
+    pub native_template: Option<String>,
+    pub scope: Option<Scope>,
+    #[serde(default = "default_sysusers")]
+    pub sysusers: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Slice {
+    pub name: String,
+    pub description: String,
+    #[serde(rename = "CPUWeight")]
+    pub cpu_weight: Option<u32>,
+    #[serde(rename = "IOWeight")]
+    pub io_weight: Option<u32>,
+    pub memory_high: Option<NumberOrString>,
+    pub memory_max: Option<NumberOrString>,
+}
+
+fn default_slices() -> Vec<Slice> {
+    vec![]
+}
+
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct NetworkMatch {
+    pub name: Option<String>,
+    #[serde(rename = "MACAddress")]
+    pub mac_address: Option<String>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct NetworkSection {
+    pub address: Option<Vec<String>>,
+    pub gateway: Option<String>,
+    #[serde(rename = "DNS")]
+    pub dns: Option<Vec<String>>,
+    #[serde(rename = "DHCP")]
+    pub dhcp: Option<String>,
+    pub bridge: Option<String>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct NetDevSection {
+    pub name: Option<String>,
+    pub kind: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct NetworkTemplateDef {
+    pub r#match: Option<NetworkMatch>,
+    pub network: Option<NetworkSection>,
+    pub net_dev: Option<NetDevSection>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct NetworkInstanceDef {
+    pub name: String,
+    pub r#match: Option<NetworkMatch>,
+    pub network: Option<NetworkSection>,
+    pub net_dev: Option<NetDevSection>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkTemplatesAndInstances {
+    pub template: NetworkTemplateDef,
+    pub instances: Vec<NetworkInstanceDef>,
+}
+
+fn default_networks() -> Vec<NetworkTemplatesAndInstances> {
+    vec![]
+}
+
+fn default_templates() -> BTreeMap<String, TemplateServiceDef> {
+    BTreeMap::new()
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DefinitionFile {
+    #[serde(default = "default_slices")]
+    pub slices: Vec<Slice>,
+    #[serde(default = "default_networks")]
+    pub networks: Vec<NetworkTemplatesAndInstances>,
+    #[serde(default = "default_templates")]
+    pub templates: BTreeMap<String, TemplateServiceDef>,
+    pub defs: Vec<TemplatesAndInstances>,
+    pub version: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl Format {
+    pub fn detect(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Format::Json,
+            Some("toml") => Format::Toml,
+            _ => Format::Yaml,
+        }
+    }
+}
+
+/// Why a run failed. Each kind exits with its own status from sysexits.h so
+/// scripts can tell them apart.
+#[derive(Debug)]
+pub enum Error {
+    /// An input file could not be read
+    Io(String),
+    /// An input file is malformed or does not match the schema
+    Parse(String),
+    /// The definitions describe units that cannot be generated
+    Validation(String),
+    /// An output file or directory could not be written
+    Write(String),
+    /// Existing output does not match the definitions, reported by `--check`
+    Outdated(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 66,
+            Error::Parse(_) => 65,
+            Error::Validation(_) => 78,
+            Error::Write(_) => 73,
+            Error::Outdated(_) => 1,
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(v)
+            | Error::Parse(v)
+            | Error::Validation(v)
+            | Error::Write(v)
+            | Error::Outdated(v) => f.write_str(v),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Copy, Debug)]
+struct ResolveOptions {
+    pub drop_in: bool,
+    pub user_scope: bool,
+}
+
+fn generated_header() -> String {
+    let mut memo = String::from("; THIS FILE IS GENERATED BY gen-systemd-svc\n");
+    memo += "; DO NOT EDIT THIS FILE DIRECTLY!\n";
+    memo
+}
+
+/// A `[Name]` section of a unit file with its directives in output order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub entries: Vec<(String, String)>,
+}
+
+impl Section {
+    pub fn new(name: &str) -> Section {
+        Section {
+            name: name.into(),
+            entries: vec![],
+        }
+    }
+
+    pub fn push(&mut self, key: &str, value: impl core::fmt::Display) {
+        self.entries.push((key.into(), value.to_string()));
+    }
+}
+
+/// A unit file built by the resolvers. `Display` renders it with the
+/// generated header, one `[Name]` block per section.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnitFile {
+    pub sections: Vec<Section>,
+}
+
+impl core::fmt::Display for UnitFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", generated_header())?;
+        for section in &self.sections {
+            write!(f, "\n[{}]\n", section.name)?;
+            for (k, v) in &section.entries {
+                writeln!(f, "{}={}", k, v)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A file produced by `render`. `Display` renders it as it is written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GeneratedFile {
+    /// A unit, drop-in, .nspawn, .network or .netdev file
+    Unit(UnitFile),
+    /// A tmpfiles.d, sysusers.d or preset file with one entry per line
+    Lines(Vec<String>),
+}
+
+impl core::fmt::Display for GeneratedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeneratedFile::Unit(v) => write!(f, "{}", v),
+            GeneratedFile::Lines(v) => {
+                writeln!(f, "# THIS FILE IS GENERATED BY gen-systemd-svc")?;
+                writeln!(f, "# DO NOT EDIT THIS FILE DIRECTLY!")?;
+                for line in v {
+                    writeln!(f, "{}", line)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Fails if a value would spill onto a line of its own once written.
+fn check_newlines(file: &str, contents: &GeneratedFile) -> Result<(), Error> {
+    let broken = match contents {
+        GeneratedFile::Unit(unit) => unit
+            .sections
+            .iter()
+            .flat_map(|s| &s.entries)
+            .find(|(k, v)| k.contains(['\n', '\r']) || v.contains(['\n', '\r']))
+            .map(|(k, _)| format!("{}=", k.escape_debug())),
+        GeneratedFile::Lines(lines) => lines
+            .iter()
+            .find(|l| l.contains(['\n', '\r']))
+            .map(|l| l.escape_debug().to_string()),
+    };
+    match broken {
+        Some(v) => Err(Error::Validation(format!(
+            "{}: {} contains a newline",
+            file, v
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn negatable_list(
+    directive: &str,
+    entries: &[String],
+    is_valid: fn(&str) -> bool,
+) -> Result<String, String> {
+    let negated = entries.iter().filter(|c| c.starts_with('~')).count();
+    if negated != 0 && negated != entries.len() {
+        return Err(format!(
+            "{}= must negate either every entry or none",
+            directive
+        ));
+    }
+
+    let names: Vec<&str> = entries.iter().map(|c| c.trim_start_matches('~')).collect();
+    if let Some(name) = names.iter().find(|n| !is_valid(n)) {
+        return Err(format!("{} is not a valid entry for {}=", name, directive));
+    }
+
+    match negated {
+        0 => Ok(names.join(" ")),
+        _ => Ok(format!("~{}", names.join(" "))),
+    }
+}
+
+fn is_valid_capability(name: &str) -> bool {
+    name.starts_with("CAP_")
+}
+
+fn is_valid_system_call(name: &str) -> bool {
+    let (name, allowed_punctuation) = match name.strip_prefix('@') {
+        Some(group) => (group, &['-', '_'][..]),
+        None => (name, &['_'][..]),
+    };
+    !name.is_empty()
+        && name.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || allowed_punctuation.contains(&c)
+        })
+}
+
+fn is_valid_address_family(name: &str) -> bool {
+    name == "none" || name.starts_with("AF_")
+}
+
+fn is_valid_namespace(name: &str) -> bool {
+    NAMESPACE_TYPES.contains(&name)
+}
+
+fn is_valid_ip_address_match(value: &str) -> bool {
+    if ["any", "localhost", "link-local", "multicast"].contains(&value) {
+        return true;
+    }
+    let (address, prefix) = match value.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (value, None),
+    };
+    let max_prefix = match address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => 32,
+        Ok(IpAddr::V6(_)) => 128,
+        Err(_) => return false,
+    };
+    prefix.is_none_or(|p| p.parse::<u8>().is_ok_and(|p| p <= max_prefix))
+}
+
+fn is_valid_device_allow(value: &str) -> bool {
+    let mut parts = value.split_whitespace();
+    let valid_node = parts
+        .next()
+        .is_some_and(|n| n.starts_with('/') || n.starts_with("char-") || n.starts_with("block-"));
+    let valid_permissions = parts
+        .next()
+        .is_none_or(|p| p.chars().all(|c| "rwm".contains(c)));
+    valid_node && valid_permissions && parts.next().is_none()
+}
+
+fn resolve_sandbox(instance_sandbox: Sandbox, template_sandbox: Sandbox) -> Sandbox {
+    let mut sandbox = template_sandbox;
+
+    if instance_sandbox.no_new_privileges.is_some() {
+        sandbox.no_new_privileges = instance_sandbox.no_new_privileges;
+    }
+    if instance_sandbox.bind_paths.is_some() {
+        sandbox.bind_paths = instance_sandbox.bind_paths;
+    }
+    if instance_sandbox.bind_read_only_paths.is_some() {
+        sandbox.bind_read_only_paths = instance_sandbox.bind_read_only_paths;
+    }
+    if instance_sandbox.device_allow.is_some() {
+        sandbox.device_allow = instance_sandbox.device_allow;
+    }
+    if instance_sandbox.device_policy.is_some() {
+        sandbox.device_policy = instance_sandbox.device_policy;
+    }
+    if instance_sandbox.inaccessible_paths.is_some() {
+        sandbox.inaccessible_paths = instance_sandbox.inaccessible_paths;
+    }
+    if instance_sandbox.ip_address_allow.is_some() {
+        sandbox.ip_address_allow = instance_sandbox.ip_address_allow;
+    }
+    if instance_sandbox.ip_address_deny.is_some() {
+        sandbox.ip_address_deny = instance_sandbox.ip_address_deny;
+    }
+    if instance_sandbox.lock_personality.is_some() {
+        sandbox.lock_personality = instance_sandbox.lock_personality;
+    }
+    if instance_sandbox.memory_deny_write_execute.is_some() {
+        sandbox.memory_deny_write_execute = instance_sandbox.memory_deny_write_execute;
+    }
+    if instance_sandbox.mount_api_vfs.is_some() {
+        sandbox.mount_api_vfs = instance_sandbox.mount_api_vfs;
+    }
+    if instance_sandbox.private_network.is_some() {
+        sandbox.private_network = instance_sandbox.private_network;
+    }
+    if instance_sandbox.private_devices.is_some() {
+        sandbox.private_devices = instance_sandbox.private_devices;
+    }
+    if instance_sandbox.private_tmp.is_some() {
+        sandbox.private_tmp = instance_sandbox.private_tmp;
+    }
+    if instance_sandbox.protect_clock.is_some() {
+        sandbox.protect_clock = instance_sandbox.protect_clock;
+    }
+    if instance_sandbox.protect_control_groups.is_some() {
+        sandbox.protect_control_groups = instance_sandbox.protect_control_groups;
+    }
+    if instance_sandbox.protect_home.is_some() {
+        sandbox.protect_home = instance_sandbox.protect_home;
+    }
+    if instance_sandbox.protect_hostname.is_some() {
+        sandbox.protect_hostname = instance_sandbox.protect_hostname;
+    }
+    if instance_sandbox.protect_kernel_logs.is_some() {
+        sandbox.protect_kernel_logs = instance_sandbox.protect_kernel_logs;
+    }
+    if instance_sandbox.protect_kernel_modules.is_some() {
+        sandbox.protect_kernel_modules = instance_sandbox.protect_kernel_modules;
+    }
+    if instance_sandbox.protect_kernel_tunables.is_some() {
+        sandbox.protect_kernel_tunables = instance_sandbox.protect_kernel_tunables;
+    }
+    if instance_sandbox.protect_system.is_some() {
+        sandbox.protect_system = instance_sandbox.protect_system;
+    }
+    if instance_sandbox.read_only_paths.is_some() {
+        sandbox.read_only_paths = instance_sandbox.read_only_paths;
+    }
+    if instance_sandbox.read_write_paths.is_some() {
+        sandbox.read_write_paths = instance_sandbox.read_write_paths;
+    }
+    if instance_sandbox.restrict_address_families.is_some() {
+        sandbox.restrict_address_families = instance_sandbox.restrict_address_families;
+    }
+    if instance_sandbox.restrict_namespaces.is_some() {
+        sandbox.restrict_namespaces = instance_sandbox.restrict_namespaces;
+    }
+    if instance_sandbox.restrict_realtime.is_some() {
+        sandbox.restrict_realtime = instance_sandbox.restrict_realtime;
+    }
+    if instance_sandbox.restrict_suid_sgid.is_some() {
+        sandbox.restrict_suid_sgid = instance_sandbox.restrict_suid_sgid;
+    }
+    if instance_sandbox.system_call_architectures.is_some() {
+        sandbox.system_call_architectures = instance_sandbox.system_call_architectures;
+    }
+    if instance_sandbox.system_call_error_number.is_some() {
+        sandbox.system_call_error_number = instance_sandbox.system_call_error_number;
+    }
+    if instance_sandbox.system_call_filter.is_some() {
+        sandbox.system_call_filter = instance_sandbox.system_call_filter;
+    }
+    if instance_sandbox.temporary_file_system.is_some() {
+        sandbox.temporary_file_system = instance_sandbox.temporary_file_system;
+    }
+
+    sandbox
+}
+
+fn resolve_sandbox_directives(sandbox: Sandbox, section: &mut Section) -> Result<(), String> {
+    let Sandbox {
+        no_new_privileges,
+        bind_paths,
+        bind_read_only_paths,
+        device_allow,
+        device_policy,
+        inaccessible_paths,
+        ip_address_allow,
+        ip_address_deny,
+        lock_personality,
+        memory_deny_write_execute,
+        mount_api_vfs,
+        private_network,
+        private_devices,
+        private_tmp,
+        protect_clock,
+        protect_control_groups,
+        protect_home,
+        protect_hostname,
+        protect_kernel_logs,
+        protect_kernel_modules,
+        protect_kernel_tunables,
+        protect_system,
+        read_only_paths,
+        read_write_paths,
+        restrict_address_families,
+        restrict_namespaces,
+        restrict_realtime,
+        restrict_suid_sgid,
+        system_call_architectures,
+        system_call_error_number,
+        system_call_filter,
+        temporary_file_system,
+    } = sandbox;
+
+    for (k, v) in [
+        ("BindPaths", &bind_paths),
+        ("BindReadOnlyPaths", &bind_read_only_paths),
+    ] {
+        if let Some(v) = v
+            .iter()
+            .flatten()
+            .find(|p| !p.trim_start_matches('-').starts_with('/'))
+        {
+            return Err(format!("{} is not an absolute path for {}=", v, k));
+        }
+    }
+
+    if let Some(v) = device_allow
+        .iter()
+        .flatten()
+        .find(|d| !is_valid_device_allow(d))
+    {
+        return Err(format!(
+            "DeviceAllow={} is not a device path followed by permissions",
+            v
+        ));
+    }
+
+    for (k, v) in [
+        ("IPAddressAllow", &ip_address_allow),
+        ("IPAddressDeny", &ip_address_deny),
+    ] {
+        if let Some(v) = v.iter().flatten().find(|a| !is_valid_ip_address_match(a)) {
+            return Err(format!("{} is not a valid address for {}=", v, k));
+        }
+    }
+
+    if let Some(BoolOrString::Text(v)) = &protect_home {
+        if !["read-only", "tmpfs"].contains(&v.as_str()) {
+            return Err(format!("ProtectHome={} is not a valid value", v));
+        }
+    }
+    if let Some(BoolOrString::Text(v)) = &protect_system {
+        if !["full", "strict"].contains(&v.as_str()) {
+            return Err(format!("ProtectSystem={} is not a valid value", v));
+        }
+    }
+
+    if let Some(v) = no_new_privileges {
+        section.push("NoNewPrivileges", v);
+    }
+    if let Some(v) = bind_paths {
+        section.push("BindPaths", v.join(" "));
+    }
+    if let Some(v) = bind_read_only_paths {
+        section.push("BindReadOnlyPaths", v.join(" "));
+    }
+    for v in device_allow.unwrap_or_default() {
+        section.push("DeviceAllow", v);
+    }
+    if let Some(v) = device_policy {
+        section.push("DevicePolicy", v);
+    }
+    if let Some(v) = inaccessible_paths {
+        section.push("InaccessiblePaths", v.join(" "));
+    }
+    if let Some(v) = ip_address_allow {
+        section.push("IPAddressAllow", v.join(" "));
+    }
+    if let Some(v) = ip_address_deny {
+        section.push("IPAddressDeny", v.join(" "));
+    }
+    if let Some(v) = lock_personality {
+        section.push("LockPersonality", v);
+    }
+    if let Some(v) = memory_deny_write_execute {
+        section.push("MemoryDenyWriteExecute", v);
+    }
+    if let Some(v) = mount_api_vfs {
+        section.push("MountAPIVFS", v);
+    }
+    if let Some(v) = private_network {
+        section.push("PrivateNetwork", v);
+    }
+    if let Some(v) = private_devices {
+        section.push("PrivateDevices", v);
+    }
+    if let Some(v) = private_tmp {
+        section.push("PrivateTmp", v);
+    }
+    if let Some(v) = protect_clock {
+        section.push("ProtectClock", v);
+    }
+    if let Some(v) = protect_control_groups {
+        section.push("ProtectControlGroups", v);
+    }
+    if let Some(v) = protect_home {
+        section.push("ProtectHome", v);
+    }
+    if let Some(v) = protect_hostname {
+        section.push("ProtectHostname", v);
+    }
+    if let Some(v) = protect_kernel_logs {
+        section.push("ProtectKernelLogs", v);
+    }
+    if let Some(v) = protect_kernel_modules {
+        section.push("ProtectKernelModules", v);
+    }
+    if let Some(v) = protect_kernel_tunables {
+        section.push("ProtectKernelTunables", v);
+    }
+    if let Some(v) = protect_system {
+        section.push("ProtectSystem", v);
+    }
+    if let Some(v) = read_only_paths {
+        section.push("ReadOnlyPaths", v.join(" "));
+    }
+    if let Some(v) = read_write_paths {
+        section.push("ReadWritePaths", v.join(" "));
+    }
+    if let Some(v) = restrict_address_families {
+        section.push(
+            "RestrictAddressFamilies",
+            negatable_list("RestrictAddressFamilies", &v, is_valid_address_family)?,
+        );
+    }
+    match restrict_namespaces {
+        Some(RestrictNamespaces::Bool(v)) => {
+            section.push("RestrictNamespaces", v);
+        }
+        Some(RestrictNamespaces::Namespaces(v)) => {
+            section.push(
+                "RestrictNamespaces",
+                negatable_list("RestrictNamespaces", &v, is_valid_namespace)?,
+            );
+        }
+        None => {}
+    }
+    if let Some(v) = restrict_realtime {
+        section.push("RestrictRealtime", v);
+    }
+    if let Some(v) = restrict_suid_sgid {
+        section.push("RestrictSUIDSGID", v);
+    }
+    if let Some(v) = system_call_architectures {
+        section.push("SystemCallArchitectures", v.join(" "));
+    }
+    if let Some(v) = system_call_error_number {
+        section.push("SystemCallErrorNumber", v);
+    }
+    if let Some(v) = system_call_filter {
+        section.push(
+            "SystemCallFilter",
+            negatable_list("SystemCallFilter", &v, is_valid_system_call)?,
+        );
+    }
+    if let Some(v) = temporary_file_system {
+        section.push("TemporaryFileSystem", v.join(" "));
+    }
+
+    Ok(())
+}
+
+fn resolve_service(
+    instance_service: Option<Service>,
+    template_service: Service,
+) -> Result<Service, String> {
+    let mut service = template_service;
+
+    if let Some(i) = instance_service {
+        if !i.unset.is_empty() {
+            service = clear_service_fields(service, &i.unset)?;
+        }
+        if i.ambient_capabilities.is_some() {
+            service.ambient_capabilities = i.ambient_capabilities;
+        }
+        if i.capability_bounding_set.is_some() {
+            service.capability_bounding_set = i.capability_bounding_set;
+        }
+        if i.bus_name.is_some() {
+            service.bus_name = i.bus_name;
+        }
+        if i.cache_directory.is_some() {
+            service.cache_directory = i.cache_directory;
+        }
+        if i.configuration_directory.is_some() {
+            service.configuration_directory = i.configuration_directory;
+        }
+        if i.coredump_filter.is_some() {
+            service.coredump_filter = i.coredump_filter;
+        }
+        if i.cpu_affinity.is_some() {
+            service.cpu_affinity = i.cpu_affinity;
+        }
+        if i.cpu_quota.is_some() {
+            service.cpu_quota = i.cpu_quota;
+        }
+        if i.cpu_scheduling_policy.is_some() {
+            service.cpu_scheduling_policy = i.cpu_scheduling_policy;
+        }
+        if i.cpu_scheduling_priority.is_some() {
+            service.cpu_scheduling_priority = i.cpu_scheduling_priority;
+        }
+        if i.cpu_weight.is_some() {
+            service.cpu_weight = i.cpu_weight;
+        }
+        if i.cpu_accounting.is_some() {
+            service.cpu_accounting = i.cpu_accounting;
+        }
+        if i.delegate.is_some() {
+            service.delegate = i.delegate;
+        }
+        if i.dynamic_user.is_some() {
+            service.dynamic_user = i.dynamic_user;
+        }
+        if let Some(environment) = i.environment {
+            service
+                .environment
+                .get_or_insert_with(BTreeMap::new)
+                .extend(environment);
+        }
+        if i.environment_file.is_some() {
+            service.environment_file = i.environment_file;
+        }
+        if i.exec_start_pre.is_some() {
+            service.exec_start_pre = i.exec_start_pre;
+        }
+        if i.exec_start.is_some() {
+            service.exec_start = i.exec_start;
+        }
+        if i.exec_start_post.is_some() {
+            service.exec_start_post = i.exec_start_post;
+        }
+        if i.exec_reload.is_some() {
+            service.exec_reload = i.exec_reload;
+        }
+        if i.exec_stop.is_some() {
+            service.exec_stop = i.exec_stop;
+        }
+        if i.exec_stop_post.is_some() {
+            service.exec_stop_post = i.exec_stop_post;
+        }
+        if i.file_descriptor_store_max.is_some() {
+            service.file_descriptor_store_max = i.file_descriptor_store_max;
+        }
+        if i.final_kill_signal.is_some() {
+            service.final_kill_signal = i.final_kill_signal;
+        }
+        if i.group.is_some() {
+            service.group = i.group;
+        }
+        if i.import_credential.is_some() {
+            service.import_credential = i.import_credential;
+        }
+        if i.io_accounting.is_some() {
+            service.io_accounting = i.io_accounting;
+        }
+        if let Some(io_read_bandwidth_max) = i.io_read_bandwidth_max {
+            service
+                .io_read_bandwidth_max
+                .get_or_insert_with(BTreeMap::new)
+                .extend(io_read_bandwidth_max);
+        }
+        if i.io_scheduling_class.is_some() {
+            service.io_scheduling_class = i.io_scheduling_class;
+        }
+        if i.io_scheduling_priority.is_some() {
+            service.io_scheduling_priority = i.io_scheduling_priority;
+        }
+        if i.io_weight.is_some() {
+            service.io_weight = i.io_weight;
+        }
+        if let Some(io_write_bandwidth_max) = i.io_write_bandwidth_max {
+            service
+                .io_write_bandwidth_max
+                .get_or_insert_with(BTreeMap::new)
+                .extend(io_write_bandwidth_max);
+        }
+        if i.ip_accounting.is_some() {
+            service.ip_accounting = i.ip_accounting;
+        }
+        if i.kill_mode.is_some() {
+            service.kill_mode = i.kill_mode;
+        }
+        if i.kill_signal.is_some() {
+            service.kill_signal = i.kill_signal;
+        }
+        if let Some(limits) = i.limits {
+            service
+                .limits
+                .get_or_insert_with(BTreeMap::new)
+                .extend(limits);
+        }
+        if let Some(load_credential) = i.load_credential {
+            service
+                .load_credential
+                .get_or_insert_with(BTreeMap::new)
+                .extend(load_credential);
+        }
+        if i.log_rate_limit_burst.is_some() {
+            service.log_rate_limit_burst = i.log_rate_limit_burst;
+        }
+        if i.log_rate_limit_interval_sec.is_some() {
+            service.log_rate_limit_interval_sec = i.log_rate_limit_interval_sec;
+        }
+        if i.logs_directory.is_some() {
+            service.logs_directory = i.logs_directory;
+        }
+        if i.memory_accounting.is_some() {
+            service.memory_accounting = i.memory_accounting;
+        }
+        if i.memory_high.is_some() {
+            service.memory_high = i.memory_high;
+        }
+        if i.memory_low.is_some() {
+            service.memory_low = i.memory_low;
+        }
+        if i.memory_max.is_some() {
+            service.memory_max = i.memory_max;
+        }
+        if i.memory_swap_max.is_some() {
+            service.memory_swap_max = i.memory_swap_max;
+        }
+        if i.nice.is_some() {
+            service.nice = i.nice;
+        }
+        if i.notify_access.is_some() {
+            service.notify_access = i.notify_access;
+        }
+        if i.oom_policy.is_some() {
+            service.oom_policy = i.oom_policy;
+        }
+        if i.oom_score_adjust.is_some() {
+            service.oom_score_adjust = i.oom_score_adjust;
+        }
+        if i.pam_name.is_some() {
+            service.pam_name = i.pam_name;
+        }
+        if i.pid_file.is_some() {
+            service.pid_file = i.pid_file;
+        }
+        if i.pass_environment.is_some() {
+            service.pass_environment = i.pass_environment;
+        }
+        if i.remain_after_exit.is_some() {
+            service.remain_after_exit = i.remain_after_exit;
+        }
+        if i.restart.is_some() {
+            service.restart = i.restart;
+        }
+        if i.restart_force_exit_status.is_some() {
+            service.restart_force_exit_status = i.restart_force_exit_status;
+        }
+        if i.restart_max_delay_sec.is_some() {
+            service.restart_max_delay_sec = i.restart_max_delay_sec;
+        }
+        if i.restart_prevent_exit_status.is_some() {
+            service.restart_prevent_exit_status = i.restart_prevent_exit_status;
+        }
+        if i.restart_sec.is_some() {
+            service.restart_sec = i.restart_sec;
+        }
+        if i.restart_steps.is_some() {
+            service.restart_steps = i.restart_steps;
+        }
+        if i.runtime_directory.is_some() {
+            service.runtime_directory = i.runtime_directory;
+        }
+        if i.runtime_max_sec.is_some() {
+            service.runtime_max_sec = i.runtime_max_sec;
+        }
+        if i.send_sighup.is_some() {
+            service.send_sighup = i.send_sighup;
+        }
+        if i.send_sigkill.is_some() {
+            service.send_sigkill = i.send_sigkill;
+        }
+        if let Some(set_credential) = i.set_credential {
+            service
+                .set_credential
+                .get_or_insert_with(BTreeMap::new)
+                .extend(set_credential);
+        }
+        if i.slice.is_some() {
+            service.slice = i.slice;
+        }
+        if i.sockets.is_some() {
+            service.sockets = i.sockets;
+        }
+        if i.standard_error.is_some() {
+            service.standard_error = i.standard_error;
+        }
+        if i.standard_input.is_some() {
+            service.standard_input = i.standard_input;
+        }
+        if i.standard_output.is_some() {
+            service.standard_output = i.standard_output;
+        }
+        if i.state_directory.is_some() {
+            service.state_directory = i.state_directory;
+        }
+        if i.success_exit_status.is_some() {
+            service.success_exit_status = i.success_exit_status;
+        }
+        if i.supplementary_groups.is_some() {
+            service.supplementary_groups = i.supplementary_groups;
+        }
+        if i.syslog_identifier.is_some() {
+            service.syslog_identifier = i.syslog_identifier;
+        }
+        if i.tasks_max.is_some() {
+            service.tasks_max = i.tasks_max;
+        }
+        if i.timeout_abort_sec.is_some() {
+            service.timeout_abort_sec = i.timeout_abort_sec;
+        }
+        if i.service_type.is_some() {
+            service.service_type = i.service_type;
+        }
+        if i.umask.is_some() {
+            service.umask = i.umask;
+        }
+        if i.unset_environment.is_some() {
+            service.unset_environment = i.unset_environment;
+        }
+        if i.timeout_start_sec.is_some() {
+            service.timeout_start_sec = i.timeout_start_sec;
+        }
+        if i.timeout_stop_sec.is_some() {
+            service.timeout_stop_sec = i.timeout_stop_sec;
+        }
+        if i.tty_path.is_some() {
+            service.tty_path = i.tty_path;
+        }
+        if i.tty_reset.is_some() {
+            service.tty_reset = i.tty_reset;
+        }
+        if i.user.is_some() {
+            service.user = i.user;
+        }
+        if i.watchdog_sec.is_some() {
+            service.watchdog_sec = i.watchdog_sec;
+        }
+        if i.watchdog_signal.is_some() {
+            service.watchdog_signal = i.watchdog_signal;
+        }
+        if i.working_directory.is_some() {
+            service.working_directory = i.working_directory;
+        }
+        if let Some(sandbox) = i.sandbox {
+            service.sandbox = Some(resolve_sandbox(
+                sandbox,
+                service.sandbox.unwrap_or_default(),
+            ));
+        }
+    }
+
+    Ok(service)
+}
+
+/// Exec*= lines accumulate across drop-ins, so a drop-in clears the vendor's
+/// commands before adding its own.
+fn push_commands(section: &mut Section, directive: &str, commands: Vec<Command>, drop_in: bool) {
+    if drop_in && !commands.is_empty() {
+        section.push(directive, "");
+    }
+    for v in commands {
+        section.push(directive, v);
+    }
+}
+
+fn resolve_service_section(
+    service: Service,
+    options: ResolveOptions,
+    warnings: &mut Vec<String>,
+) -> Result<Section, String> {
+    let Service {
+        ambient_capabilities,
+        capability_bounding_set,
+        bus_name,
+        cache_directory,
+        configuration_directory,
+        coredump_filter,
+        cpu_affinity,
+        cpu_quota,
+        cpu_scheduling_policy,
+        cpu_scheduling_priority,
+        cpu_weight,
+        cpu_accounting,
+        delegate,
+        dynamic_user,
+        environment,
+        environment_file,
+        exec_start_pre,
+        exec_start,
+        exec_start_post,
+        exec_reload,
+        exec_stop,
+        exec_stop_post,
+        file_descriptor_store_max,
+        final_kill_signal,
+        group,
+        import_credential,
+        io_accounting,
+        io_read_bandwidth_max,
+        io_scheduling_class,
+        io_scheduling_priority,
+        io_weight,
+        io_write_bandwidth_max,
+        ip_accounting,
+        kill_mode,
+        kill_signal,
+        limits,
+        load_credential,
+        log_rate_limit_burst,
+        log_rate_limit_interval_sec,
+        logs_directory,
+        memory_accounting,
+        memory_high,
+        memory_low,
+        memory_max,
+        memory_swap_max,
+        nice,
+        notify_access,
+        oom_policy,
+        oom_score_adjust,
+        pam_name,
+        pid_file,
+        pass_environment,
+        mut remain_after_exit,
+        restart,
+        restart_force_exit_status,
+        restart_max_delay_sec,
+        restart_prevent_exit_status,
+        restart_sec,
+        restart_steps,
+        runtime_directory,
+        runtime_max_sec,
+        send_sighup,
+        send_sigkill,
+        set_credential,
+        slice,
+        sockets,
+        standard_error,
+        standard_input,
+        standard_output,
+        state_directory,
+        success_exit_status,
+        supplementary_groups,
+        syslog_identifier,
+        tasks_max,
+        timeout_abort_sec,
+        service_type,
+        umask,
+        unset_environment,
+        timeout_start_sec,
+        timeout_stop_sec,
+        tty_path,
+        tty_reset,
+        user,
+        watchdog_sec,
+        watchdog_signal,
+        working_directory,
+        sandbox,
+        unset: _,
+    } = service;
+
+    if options.user_scope {
+        if user.is_some() {
+            return Err("User= is not supported in user units".into());
+        }
+        if group.is_some() {
+            return Err("Group= is not supported in user units".into());
+        }
+        if supplementary_groups.is_some() {
+            return Err("SupplementaryGroups= is not supported in user units".into());
+        }
+    }
+
+    // a drop-in without Type= inherits whatever the vendor unit declares
+    let check_type = !options.drop_in || service_type.is_some();
+
+    let exec_start = exec_start.map(OneOrMany::into_vec).unwrap_or_default();
+    if check_type && exec_start.len() > 1 && !matches!(service_type, Some(ServiceType::OneShot)) {
+        return Err("multiple ExecStart= entries are only allowed with Type=oneshot".into());
+    }
+
+    if dynamic_user == Some(true) {
+        if user.is_some() {
+            return Err("User= cannot be combined with DynamicUser=true".into());
+        }
+        if let Some(v) = &working_directory {
+            if v.starts_with('/') {
+                warnings.push(format!(
+                    "WorkingDirectory={} may not be writable with DynamicUser=true, consider StateDirectory= instead",
+                    v
+                ));
+            }
+        }
+    }
+
+    if restart_steps.is_some() && restart_max_delay_sec.is_none() {
+        return Err("RestartSteps= requires RestartMaxDelaySec= to be set".into());
+    }
+
+    if let Some(v) = &cpu_quota {
+        let valid = v
+            .strip_suffix('%')
+            .is_some_and(|pct| !pct.is_empty() && pct.parse::<f64>().is_ok_and(|p| p >= 0.0));
+        if !valid {
+            return Err(format!("CPUQuota={} is not a valid percentage", v));
+        }
+    }
+    if let Some(v) = cpu_weight {
+        if !(1..=10000).contains(&v) {
+            return Err(format!("CPUWeight={} must be between 1 and 10000", v));
+        }
+    }
+
+    for (k, v) in [
+        ("LogRateLimitIntervalSec", &log_rate_limit_interval_sec),
+        ("RestartMaxDelaySec", &restart_max_delay_sec),
+        ("RestartSec", &restart_sec),
+        ("RuntimeMaxSec", &runtime_max_sec),
+        ("TimeoutAbortSec", &timeout_abort_sec),
+        ("TimeoutStartSec", &timeout_start_sec),
+        ("TimeoutStopSec", &timeout_stop_sec),
+        ("WatchdogSec", &watchdog_sec),
+    ] {
+        if let Some(v) = v {
+            if !is_valid_time_span(v) {
+                return Err(format!("{}={} is not a valid time span", k, v));
+            }
+        }
+    }
+
+    for (k, v) in [
+        ("RestartForceExitStatus", &restart_force_exit_status),
+        ("RestartPreventExitStatus", &restart_prevent_exit_status),
+        ("SuccessExitStatus", &success_exit_status),
+    ] {
+        if let Some(v) = v.iter().flatten().find(|s| !is_valid_exit_status(s)) {
+            return Err(format!(
+                "{} is not a valid exit status or signal for {}=",
+                v, k
+            ));
+        }
+    }
+
+    for (k, v) in [
+        ("FinalKillSignal", &final_kill_signal),
+        ("KillSignal", &kill_signal),
+        ("WatchdogSignal", &watchdog_signal),
+    ] {
+        if let Some(v) = v {
+            if !is_valid_signal(v) {
+                return Err(format!("{}={} is not a valid signal name", k, v));
+            }
+        }
+    }
+
+    for (k, v) in [
+        ("StandardError", &standard_error),
+        ("StandardOutput", &standard_output),
+    ] {
+        if let Some(v) = v {
+            if !is_valid_output(v) {
+                return Err(format!("{}={} is not a valid output", k, v));
+            }
+        }
+    }
+
+    if let Some(v) = &standard_input {
+        if !is_valid_input(v) {
+            return Err(format!("StandardInput={} is not a valid input", v));
+        }
+    }
+
+    let uses_tty = [&standard_input, &standard_output, &standard_error]
+        .into_iter()
+        .flatten()
+        .any(|v| v.starts_with("tty"));
+    if !options.drop_in && (tty_path.is_some() || tty_reset.is_some()) && !uses_tty {
+        warnings.push("TTYPath=/TTYReset= have no effect unless a standard stream is tty".into());
+    }
+
+    if let Some(Delegate::Controllers(v)) = &delegate {
+        if let Some(c) = v
+            .iter()
+            .find(|c| !DELEGATE_CONTROLLERS.contains(&c.as_str()))
+        {
+            return Err(format!(
+                "{} is not a cgroup controller that can be delegated",
+                c
+            ));
+        }
+    }
+
+    if let Some(v) = nice {
+        if !(-20..=19).contains(&v) {
+            return Err(format!("Nice={} must be between -20 and 19", v));
+        }
+    }
+
+    if let Some(v) = io_scheduling_priority {
+        if v > 7 {
+            return Err(format!(
+                "IOSchedulingPriority={} must be between 0 and 7",
+                v
+            ));
+        }
+    }
+
+    if let Some(v) = cpu_scheduling_priority {
+        if !(1..=99).contains(&v) {
+            return Err(format!(
+                "CPUSchedulingPriority={} must be between 1 and 99",
+                v
+            ));
+        }
+        if !matches!(
+            cpu_scheduling_policy,
+            Some(CpuSchedulingPolicy::Fifo | CpuSchedulingPolicy::Rr)
+        ) {
+            warnings.push(
+                "CPUSchedulingPriority= only applies to CPUSchedulingPolicy=fifo or rr".into(),
+            );
+        }
+    }
+
+    if let Some(v) = oom_score_adjust {
+        if !(-1000..=1000).contains(&v) {
+            return Err(format!(
+                "OOMScoreAdjust={} must be between -1000 and 1000",
+                v
+            ));
+        }
+    }
+
+    if let Some(v) = &umask {
+        if !(3..=4).contains(&v.len()) || !v.chars().all(|c| ('0'..='7').contains(&c)) {
+            return Err(format!("UMask={} is not an octal mode such as 0027", v));
+        }
+    }
+
+    if let Some(v) = io_weight {
+        if !(1..=10000).contains(&v) {
+            return Err(format!("IOWeight={} must be between 1 and 10000", v));
+        }
+    }
+
+    if let Some(NumberOrString::Text(v)) = &tasks_max {
+        let valid = v == "infinity"
+            || v.strip_suffix('%')
+                .is_some_and(|pct| pct.parse::<f64>().is_ok_and(|p| (0.0..=100.0).contains(&p)));
+        if !valid {
+            return Err(format!("TasksMax={} is not a valid task count", v));
+        }
+    }
+
+    for (k, v) in limits.iter().flatten() {
+        if !k.starts_with("Limit") {
+            return Err(format!("{} is not a Limit* directive", k));
+        }
+        if !is_valid_limit(v) {
+            return Err(format!("{}={} is not a valid resource limit", k, v));
+        }
+    }
+
+    for (k, v) in [
+        ("MemoryHigh", &memory_high),
+        ("MemoryLow", &memory_low),
+        ("MemoryMax", &memory_max),
+        ("MemorySwapMax", &memory_swap_max),
+    ] {
+        if let Some(v) = v {
+            if !is_valid_memory(v) {
+                return Err(format!("{}={} is not a valid memory size", k, v));
+            }
+        }
+    }
+
+    if check_type && matches!(service_type, Some(ServiceType::Forking)) && pid_file.is_none() {
+        warnings.push("Type=forking is set without PIDFile=".into());
+    }
+
+    if check_type {
+        let dbus = matches!(service_type, Some(ServiceType::DBus));
+        if dbus && bus_name.is_none() {
+            return Err("Type=dbus requires BusName=".into());
+        }
+        if !dbus && bus_name.is_some() {
+            warnings.push("BusName= is only used with Type=dbus".into());
+        }
+    }
+
+    if check_type
+        && watchdog_sec.is_some()
+        && !matches!(
+            service_type,
+            Some(ServiceType::Notify | ServiceType::NotifyReload)
+        )
+    {
+        return Err("WatchdogSec= is only supported with Type=notify or Type=notify-reload".into());
+    }
+
+    if !options.drop_in {
+        remain_after_exit = remain_after_exit.or_else(default_remain_after_exit);
+    }
+
+    if let Some(v) = load_credential
+        .iter()
+        .flatten()
+        .chain(set_credential.iter().flatten())
+        .map(|(k, _)| k)
+        .find(|k| k.is_empty() || k.contains(['/', ':']))
+    {
+        return Err(format!("{} is not a valid credential name", v));
+    }
+
+    let mut section = Section::new("Service");
+
+    if let Some(v) = ambient_capabilities {
+        section.push(
+            "AmbientCapabilities",
+            negatable_list("AmbientCapabilities", &v, is_valid_capability)?,
+        );
+    }
+    if let Some(v) = capability_bounding_set {
+        section.push(
+            "CapabilityBoundingSet",
+            negatable_list("CapabilityBoundingSet", &v, is_valid_capability)?,
+        );
+    }
+    if let Some(v) = bus_name {
+        section.push("BusName", v);
+    }
+    if let Some(v) = cache_directory {
+        section.push("CacheDirectory", v.into_vec().join(" "));
+    }
+    if let Some(v) = configuration_directory {
+        section.push("ConfigurationDirectory", v.into_vec().join(" "));
+    }
+    if let Some(v) = coredump_filter {
+        section.push("CoredumpFilter", v.into_vec().join(" "));
+    }
+    if let Some(v) = cpu_affinity {
+        let cpus: Vec<String> = v.iter().map(|c| c.to_string()).collect();
+        section.push("CPUAffinity", cpus.join(" "));
+    }
+    if let Some(v) = cpu_quota {
+        section.push("CPUQuota", v);
+    }
+    if let Some(v) = cpu_scheduling_policy {
+        section.push("CPUSchedulingPolicy", v);
+    }
+    if let Some(v) = cpu_scheduling_priority {
+        section.push("CPUSchedulingPriority", v);
+    }
+    if let Some(v) = cpu_weight {
+        section.push("CPUWeight", v);
+    }
+    if let Some(v) = cpu_accounting {
+        section.push("CPUAccounting", v);
+    }
+    if let Some(v) = delegate {
+        section.push("Delegate", v);
+    }
+    if let Some(v) = dynamic_user {
+        section.push("DynamicUser", v);
+    }
+    for (k, v) in environment.unwrap_or_default() {
+        if k.is_empty() || k.contains(|c: char| c == '=' || c.is_whitespace()) {
+            return Err(format!(
+                "Environment key {:?} must not be empty or contain = or whitespace",
+                k
+            ));
+        }
+        // systemd expands specifiers in Environment= but not variables
+        section.push(
+            "Environment",
+            quote(&escape_arg(&format!("{}={}", k, v), false)),
+        );
+    }
+    for v in environment_file
+        .map(OneOrMany::into_vec)
+        .unwrap_or_default()
+    {
+        section.push("EnvironmentFile", v);
+    }
+    let drop_in = options.drop_in;
+    push_commands(
+        &mut section,
+        "ExecStartPre",
+        exec_start_pre.map(OneOrMany::into_vec).unwrap_or_default(),
+        drop_in,
+    );
+    push_commands(&mut section, "ExecStart", exec_start, drop_in);
+    push_commands(
+        &mut section,
+        "ExecStartPost",
+        exec_start_post.into_iter().collect(),
+        drop_in,
+    );
+    push_commands(
+        &mut section,
+        "ExecReload",
+        exec_reload.into_iter().collect(),
+        drop_in,
+    );
+    push_commands(
+        &mut section,
+        "ExecStop",
+        exec_stop.into_iter().collect(),
+        drop_in,
+    );
+    push_commands(
+        &mut section,
+        "ExecStopPost",
+        exec_stop_post.into_iter().collect(),
+        drop_in,
+    );
+    if let Some(v) = file_descriptor_store_max {
+        section.push("FileDescriptorStoreMax", v);
+    }
+    if let Some(v) = final_kill_signal {
+        section.push("FinalKillSignal", v);
+    }
+    if let Some(v) = group {
+        section.push("Group", v);
+    }
+    for v in import_credential.unwrap_or_default() {
+        section.push("ImportCredential", v);
+    }
+    if let Some(v) = io_accounting {
+        section.push("IOAccounting", v);
+    }
+    for (k, v) in io_read_bandwidth_max.unwrap_or_default() {
+        section.push("IOReadBandwidthMax", format!("{} {}", k, v));
+    }
+    if let Some(v) = io_scheduling_class {
+        section.push("IOSchedulingClass", v);
+    }
+    if let Some(v) = io_scheduling_priority {
+        section.push("IOSchedulingPriority", v);
+    }
+    if let Some(v) = io_weight {
+        section.push("IOWeight", v);
+    }
+    for (k, v) in io_write_bandwidth_max.unwrap_or_default() {
+        section.push("IOWriteBandwidthMax", format!("{} {}", k, v));
+    }
+    if let Some(v) = ip_accounting {
+        section.push("IPAccounting", v);
+    }
+    if let Some(v) = kill_mode {
+        section.push("KillMode", v);
+    }
+    if let Some(v) = kill_signal {
+        section.push("KillSignal", v);
+    }
+    for (k, v) in limits.unwrap_or_default() {
+        section.push(&k, v);
+    }
+    for (k, v) in load_credential.unwrap_or_default() {
+        section.push("LoadCredential", format!("{}:{}", k, v));
+    }
+    if let Some(v) = log_rate_limit_burst {
+        section.push("LogRateLimitBurst", v);
+    }
+    if let Some(v) = log_rate_limit_interval_sec {
+        section.push("LogRateLimitIntervalSec", v);
+    }
+    if let Some(v) = logs_directory {
+        section.push("LogsDirectory", v.into_vec().join(" "));
+    }
+    if let Some(v) = memory_accounting {
+        section.push("MemoryAccounting", v);
+    }
+    if let Some(v) = memory_high {
+        section.push("MemoryHigh", v);
+    }
+    if let Some(v) = memory_low {
+        section.push("MemoryLow", v);
+    }
+    if let Some(v) = memory_max {
+        section.push("MemoryMax", v);
+    }
+    if let Some(v) = memory_swap_max {
+        section.push("MemorySwapMax", v);
+    }
+    if let Some(v) = nice {
+        section.push("Nice", v);
+    }
+    if let Some(v) = notify_access {
+        section.push("NotifyAccess", v);
+    }
+    if let Some(v) = oom_policy {
+        section.push("OOMPolicy", v);
+    }
+    if let Some(v) = oom_score_adjust {
+        section.push("OOMScoreAdjust", v);
+    }
+    if let Some(v) = pam_name {
+        section.push("PAMName", v);
+    }
+    if let Some(v) = pid_file {
+        section.push("PIDFile", v);
+    }
+    if let Some(v) = pass_environment {
+        section.push("PassEnvironment", v.join(" "));
+    }
+    if let Some(v) = remain_after_exit {
+        section.push("RemainAfterExit", v);
+    }
+    if let Some(v) = restart {
+        section.push("Restart", v);
+    }
+    if let Some(v) = restart_force_exit_status {
+        section.push("RestartForceExitStatus", exit_status_list(&v));
+    }
+    if let Some(v) = restart_max_delay_sec {
+        section.push("RestartMaxDelaySec", v);
+    }
+    if let Some(v) = restart_prevent_exit_status {
+        section.push("RestartPreventExitStatus", exit_status_list(&v));
+    }
+    if let Some(v) = restart_sec {
+        section.push("RestartSec", v);
+    }
+    if let Some(v) = restart_steps {
+        section.push("RestartSteps", v);
+    }
+    if let Some(v) = runtime_directory {
+        section.push("RuntimeDirectory", v.into_vec().join(" "));
+    }
+    if let Some(v) = runtime_max_sec {
+        section.push("RuntimeMaxSec", v);
+    }
+    if let Some(v) = send_sighup {
+        section.push("SendSIGHUP", v);
+    }
+    if let Some(v) = send_sigkill {
+        section.push("SendSIGKILL", v);
+    }
+    for (k, v) in set_credential.unwrap_or_default() {
+        section.push("SetCredential", format!("{}:{}", k, v));
+    }
+    if let Some(v) = slice {
+        section.push("Slice", v);
+    }
+    if let Some(v) = sockets {
+        let units: Vec<String> = v
+            .into_iter()
+            .map(|s| match s.ends_with(".socket") {
+                true => s,
+                false => format!("{}.socket", s),
+            })
+            .collect();
+        section.push("Sockets", units.join(" "));
+    }
+    if let Some(v) = standard_error {
+        section.push("StandardError", v);
+    }
+    if let Some(v) = standard_input {
+        section.push("StandardInput", v);
+    }
+    if let Some(v) = standard_output {
+        section.push("StandardOutput", v);
+    }
+    if let Some(v) = state_directory {
+        section.push("StateDirectory", v.into_vec().join(" "));
+    }
+    if let Some(v) = success_exit_status {
+        section.push("SuccessExitStatus", exit_status_list(&v));
+    }
+    if let Some(v) = supplementary_groups {
+        section.push("SupplementaryGroups", v.join(" "));
+    }
+    if let Some(v) = syslog_identifier {
+        section.push("SyslogIdentifier", v);
+    }
+    if let Some(v) = tasks_max {
+        section.push("TasksMax", v);
+    }
+    if let Some(v) = timeout_abort_sec {
+        section.push("TimeoutAbortSec", v);
+    }
+    if let Some(v) = timeout_start_sec {
+        section.push("TimeoutStartSec", v);
+    }
+    if let Some(v) = timeout_stop_sec {
+        section.push("TimeoutStopSec", v);
+    }
+    if let Some(v) = tty_path {
+        section.push("TTYPath", v);
+    }
+    if let Some(v) = tty_reset {
+        section.push("TTYReset", v);
+    }
+    if let Some(v) = service_type {
+        section.push("Type", v);
+    }
+    if let Some(v) = umask {
+        section.push("UMask", v);
+    }
+    if let Some(v) = unset_environment {
+        section.push("UnsetEnvironment", v.join(" "));
+    }
+    if let Some(v) = user {
+        section.push("User", v);
+    }
+    if let Some(v) = watchdog_sec {
+        section.push("WatchdogSec", v);
+    }
+    if let Some(v) = watchdog_signal {
+        section.push("WatchdogSignal", v);
+    }
+    if let Some(v) = working_directory {
+        section.push("WorkingDirectory", v);
+    }
+    if let Some(v) = sandbox {
+        resolve_sandbox_directives(v, &mut section)?;
+    }
+
+    Ok(section)
+}
+
+fn check_unit_action(
+    directive: &str,
+    action: &UnitAction,
+    options: ResolveOptions,
+) -> Result<(), String> {
+    let allowed = matches!(
+        action,
+        UnitAction::None | UnitAction::Exit | UnitAction::ExitForce
+    );
+    if options.user_scope && !allowed {
+        return Err(format!(
+            "{}={} is not supported in user units",
+            directive, action
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+    Append,
+    Prepend,
+    Replace,
+}
+
+const MERGEABLE_DEPS: &[&str] = &[
+    "Documentation",
+    "Requires",
+    "After",
+    "Before",
+    "Wants",
+    "Conflicts",
+    "Upholds",
+    "OnFailure",
+    "OnSuccess",
+    "PropagatesReloadTo",
+    "ReloadPropagatedFrom",
+    "RequiresMountsFor",
+];
+
+fn merge_deps(
+    inherit: bool,
+    dedupe: bool,
+    strategy: Option<&MergeStrategy>,
+    template: &[String],
+    instance: Option<Vec<String>>,
+) -> Vec<String> {
+    let merged = match (
+        inherit,
+        strategy.unwrap_or(&MergeStrategy::Append),
+        instance,
+    ) {
+        (false, _, instance) => instance.unwrap_or_default(),
+        (true, MergeStrategy::Append, instance) => {
+            let mut v = template.to_vec();
+            v.extend(instance.unwrap_or_default());
+            v
+        }
+        (true, MergeStrategy::Prepend, instance) => {
+            let mut v = instance.unwrap_or_default();
+            v.extend_from_slice(template);
+            v
+        }
+        (true, MergeStrategy::Replace, Some(instance)) => instance,
+        (true, MergeStrategy::Replace, None) => template.to_vec(),
+    };
+
+    dedupe_list(dedupe, merged)
+}
+
+/// Drops all but the first of each value when `dedupe` is set.
+fn dedupe_list(dedupe: bool, values: Vec<String>) -> Vec<String> {
+    if !dedupe {
+        return values;
+    }
+    let mut seen = BTreeSet::new();
+    values
+        .into_iter()
+        .filter(|v| seen.insert(v.clone()))
+        .collect()
+}
+
+fn unit_checks(
+    prefix: &str,
+    checks: BTreeMap<String, OneOrMany<BoolOrString>>,
+    section: &mut Section,
+) -> Result<(), String> {
+    for (k, v) in checks {
+        if !k.starts_with(prefix) || k.len() == prefix.len() {
+            return Err(format!("{} is not a {}*= directive", k, prefix));
+        }
+        for v in v.into_vec() {
+            section.push(&k, v);
+        }
+    }
+    Ok(())
+}
+
+fn resolve(
+    instance: InstanceServiceDef,
+    template: TemplateServiceDef,
+    target: Option<&str>,
+    socket: Option<&str>,
+    options: ResolveOptions,
+    warnings: &mut Vec<String>,
+) -> Result<UnitFile, String> {
+    let mut unit = Section::new("Unit");
+    match &instance.unit.description {
+        Some(v) => unit.push("Description", v),
+        None if options.drop_in => {}
+        None => return Err("Description is required unless the unit is a drop-in".into()),
+    }
+
+    let merge = instance.unit.merge.clone().unwrap_or_default();
+    if let Some(k) = merge.keys().find(|k| !MERGEABLE_DEPS.contains(&k.as_str())) {
+        return Err(format!("{}= does not support a merge strategy", k));
+    }
+
+    let documentation = merge_deps(
+        instance.unit.inherit_documentation,
+        instance.unit.dedupe,
+        merge.get("Documentation"),
+        &template.unit.documentation,
+        instance.unit.documentation,
+    );
+
+    for v in documentation {
+        unit.push("Documentation", v);
+    }
+
+    let mut requires = merge_deps(
+        instance.unit.inherit_requires,
+        instance.unit.dedupe,
+        merge.get("Requires"),
+        &template.unit.requires,
+        instance.unit.requires,
+    );
+    if let Some(socket) = socket.filter(|s| !requires.iter().any(|v| v == s)) {
+        requires.push(socket.to_string());
+    }
+
+    for req in requires {
+        unit.push("Requires", req);
+    }
+
+    let mut afters = merge_deps(
+        instance.unit.inherit_after,
+        instance.unit.dedupe,
+        merge.get("After"),
+        &template.unit.after,
+        instance.unit.after,
+    );
+    if let Some(socket) = socket.filter(|s| !afters.iter().any(|v| v == s)) {
+        afters.push(socket.to_string());
+    }
+
+    for after in afters {
+        unit.push("After", after);
+    }
+
+    let before = merge_deps(
+        instance.unit.inherit_before,
+        instance.unit.dedupe,
+        merge.get("Before"),
+        &template.unit.before,
+        instance.unit.before,
+    );
+
+    for v in before {
+        unit.push("Before", v);
+    }
+
+    let wants = merge_deps(
+        instance.unit.inherit_wants,
+        instance.unit.dedupe,
+        merge.get("Wants"),
+        &template.unit.wants,
+        instance.unit.wants,
+    );
+
+    for want in wants {
+        unit.push("Wants", want);
+    }
+
+    let conflicts = merge_deps(
+        instance.unit.inherit_conflicts,
+        instance.unit.dedupe,
+        merge.get("Conflicts"),
+        &template.unit.conflicts,
+        instance.unit.conflicts,
+    );
+
+    for v in conflicts {
+        unit.push("Conflicts", v);
+    }
+
+    let upholds = merge_deps(
+        instance.unit.inherit_upholds,
+        instance.unit.dedupe,
+        merge.get("Upholds"),
+        &template.unit.upholds,
+        instance.unit.upholds,
+    );
+
+    for v in upholds {
+        unit.push("Upholds", v);
+    }
+
+    let on_failure = merge_deps(
+        instance.unit.inherit_on_failure,
+        instance.unit.dedupe,
+        merge.get("OnFailure"),
+        &template.unit.on_failure,
+        instance.unit.on_failure,
+    );
+
+    for v in on_failure {
+        unit.push("OnFailure", v);
+    }
+
+    let on_success = merge_deps(
+        instance.unit.inherit_on_success,
+        instance.unit.dedupe,
+        merge.get("OnSuccess"),
+        &template.unit.on_success,
+        instance.unit.on_success,
+    );
+
+    for v in on_success {
+        unit.push("OnSuccess", v);
+    }
+
+    let propagates_reload_to = merge_deps(
+        instance.unit.inherit_propagates_reload_to,
+        instance.unit.dedupe,
+        merge.get("PropagatesReloadTo"),
+        &template.unit.propagates_reload_to,
+        instance.unit.propagates_reload_to,
+    );
+
+    for v in propagates_reload_to {
+        unit.push("PropagatesReloadTo", v);
+    }
+
+    let reload_propagated_from = merge_deps(
+        instance.unit.inherit_reload_propagated_from,
+        instance.unit.dedupe,
+        merge.get("ReloadPropagatedFrom"),
+        &template.unit.reload_propagated_from,
+        instance.unit.reload_propagated_from,
+    );
+
+    for v in reload_propagated_from {
+        unit.push("ReloadPropagatedFrom", v);
+    }
+
+    let requires_mounts_for = merge_deps(
+        instance.unit.inherit_requires_mounts_for,
+        instance.unit.dedupe,
+        merge.get("RequiresMountsFor"),
+        &template.unit.requires_mounts_for,
+        instance.unit.requires_mounts_for,
+    );
+
+    if !requires_mounts_for.is_empty() {
+        unit.push("RequiresMountsFor", requires_mounts_for.join(" "));
+    }
+
+    for part_of in instance.unit.part_of.unwrap_or_default() {
+        unit.push("PartOf", part_of);
+    }
+
+    let start_limit_interval_sec = instance
+        .unit
+        .start_limit_interval_sec
+        .or(template.unit.start_limit_interval_sec);
+    if let Some(v) = start_limit_interval_sec {
+        if !is_valid_time_span(&v) {
+            return Err(format!(
+                "StartLimitIntervalSec={} is not a valid time span",
+                v
+            ));
+        }
+        unit.push("StartLimitIntervalSec", v);
+    }
+    if let Some(v) = instance
+        .unit
+        .start_limit_burst
+        .or(template.unit.start_limit_burst)
+    {
+        unit.push("StartLimitBurst", v);
+    }
+    if let Some(v) = instance
+        .unit
+        .start_limit_action
+        .or(template.unit.start_limit_action)
+    {
+        check_unit_action("StartLimitAction", &v, options)?;
+        unit.push("StartLimitAction", v);
+    }
+
+    if let Some(v) = instance
+        .unit
+        .default_dependencies
+        .or(template.unit.default_dependencies)
+    {
+        unit.push("DefaultDependencies", v);
+    }
+
+    if let Some(v) = instance
+        .unit
+        .stop_when_unneeded
+        .or(template.unit.stop_when_unneeded)
+    {
+        unit.push("StopWhenUnneeded", v);
+    }
+
+    if let Some(v) = instance
+        .unit
+        .refuse_manual_start
+        .or(template.unit.refuse_manual_start)
+    {
+        unit.push("RefuseManualStart", v);
+    }
+
+    if let Some(v) = instance
+        .unit
+        .refuse_manual_stop
+        .or(template.unit.refuse_manual_stop)
+    {
+        unit.push("RefuseManualStop", v);
+    }
+
+    if let Some(v) = instance
+        .unit
+        .job_timeout_sec
+        .or(template.unit.job_timeout_sec)
+    {
+        if !is_valid_time_span(&v) {
+            return Err(format!("JobTimeoutSec={} is not a valid time span", v));
+        }
+        unit.push("JobTimeoutSec", v);
+    }
+
+    if let Some(v) = instance
+        .unit
+        .job_timeout_action
+        .or(template.unit.job_timeout_action)
+    {
+        check_unit_action("JobTimeoutAction", &v, options)?;
+        unit.push("JobTimeoutAction", v);
+    }
+
+    if let Some(v) = instance
+        .unit
+        .success_action
+        .or(template.unit.success_action)
+    {
+        check_unit_action("SuccessAction", &v, options)?;
+        unit.push("SuccessAction", v);
+    }
+
+    if let Some(v) = instance
+        .unit
+        .failure_action
+        .or(template.unit.failure_action)
+    {
+        check_unit_action("FailureAction", &v, options)?;
+        unit.push("FailureAction", v);
+    }
+
+    if let Some(v) = instance
+        .unit
+        .ignore_on_isolate
+        .or(template.unit.ignore_on_isolate)
+    {
+        unit.push("IgnoreOnIsolate", v);
+    }
+
+    if let Some(v) = instance.unit.collect_mode.or(template.unit.collect_mode) {
+        unit.push("CollectMode", v);
+    }
+
+    let mut conditions = template.unit.conditions.unwrap_or_default();
+    conditions.extend(instance.unit.conditions.unwrap_or_default());
+    unit_checks("Condition", conditions, &mut unit)?;
+
+    let mut asserts = template.unit.asserts.unwrap_or_default();
+    asserts.extend(instance.unit.asserts.unwrap_or_default());
+    unit_checks("Assert", asserts, &mut unit)?;
+    // a drop-in only carries the directives it sets
+    let mut unit_file = UnitFile::default();
+    if !unit.entries.is_empty() {
+        unit_file.sections.push(unit);
+    }
+
+    // SERVICE PART
+    let template_service = match instance.inherit_service {
+        true => template.service,
+        false => Service::default(),
+    };
+    let service = resolve_service(instance.service, template_service)?;
+    let section = resolve_service_section(service, options, warnings)?;
+    if !section.entries.is_empty() {
+        unit_file.sections.push(section);
+    }
+
+    // INSTALL PART
+    let install = match (instance.install, template.install) {
+        (Some(i), Some(t)) if instance.inherit_install => Some(merge_install(i, t)),
+        (i, t) => i.or(t),
+    };
+    let install = match (options.drop_in, target) {
+        (true, None) => install,
+        _ => Some(install.unwrap_or_else(default_install)),
+    };
+    if let Some(install) = install {
+        let mut section = Section::new("Install");
+        // a def's target is wanted by default instead of multi-user.target
+        let mut wanted_by = match (install.wanted_by, target) {
+            (Some(v), _) => v.into_vec(),
+            (None, Some(_)) => vec![],
+            (None, None) => default_install_wanted_by().into_vec(),
+        };
+        if let Some(target) = target.filter(|t| !wanted_by.iter().any(|v| v == t)) {
+            wanted_by.push(target.to_string());
+        }
+        let dedupe = instance.unit.dedupe;
+        for v in dedupe_list(dedupe, wanted_by) {
+            section.push("WantedBy", v);
+        }
+        for v in dedupe_list(dedupe, install.required_by.unwrap_or_default()) {
+            section.push("RequiredBy", v);
+        }
+        for v in dedupe_list(dedupe, install.also.unwrap_or_default()) {
+            section.push("Also", v);
+        }
+        for v in dedupe_list(dedupe, install.alias.unwrap_or_default()) {
+            if !v.ends_with(".service") {
+                return Err(format!("Alias={} must end in .service", v));
+            }
+            section.push("Alias", v);
+        }
+        if let Some(v) = install.default_instance {
+            if !instance.unit.name.ends_with('@') {
+                return Err("DefaultInstance= is only supported for native template units".into());
+            }
+            section.push("DefaultInstance", v);
+        }
+        unit_file.sections.push(section);
+    }
+    Ok(unit_file)
+}
+
+fn merge_timer(instance: Option<Timer>, template: Option<Timer>) -> Option<Timer> {
+    if instance.is_none() && template.is_none() {
+        return None;
+    }
+    let (i, t) = (instance.unwrap_or_default(), template.unwrap_or_default());
+    Some(Timer {
+        on_calendar: i.on_calendar.or(t.on_calendar),
+        on_boot_sec: i.on_boot_sec.or(t.on_boot_sec),
+        on_unit_active_sec: i.on_unit_active_sec.or(t.on_unit_active_sec),
+        persistent: i.persistent.or(t.persistent),
+    })
+}
+
+fn merge_socket(instance: Option<Socket>, template: Option<Socket>) -> Option<Socket> {
+    if instance.is_none() && template.is_none() {
+        return None;
+    }
+    let (i, t) = (instance.unwrap_or_default(), template.unwrap_or_default());
+    Some(Socket {
+        listen_stream: i.listen_stream.or(t.listen_stream),
+        listen_datagram: i.listen_datagram.or(t.listen_datagram),
+        socket_user: i.socket_user.or(t.socket_user),
+        socket_mode: i.socket_mode.or(t.socket_mode),
+        accept: i.accept.or(t.accept),
+    })
+}
+
+fn merge_nspawn(instance: Option<Nspawn>, template: Option<Nspawn>) -> Option<Nspawn> {
+    if instance.is_none() && template.is_none() {
+        return None;
+    }
+    let (i, t) = (instance.unwrap_or_default(), template.unwrap_or_default());
+    Some(Nspawn {
+        boot: i.boot.or(t.boot),
+        bind: i.bind.or(t.bind),
+        bind_read_only: i.bind_read_only.or(t.bind_read_only),
+        private_network: i.private_network.or(t.private_network),
+        virtual_ethernet: i.virtual_ethernet.or(t.virtual_ethernet),
+        bridge: i.bridge.or(t.bridge),
+        port: i.port.or(t.port),
+    })
+}
+
+fn resolve_timer(
+    instance_timer: Option<Timer>,
+    template_timer: Option<Timer>,
+    description: &str,
+) -> Option<UnitFile> {
+    let Timer {
+        on_calendar,
+        on_boot_sec,
+        on_unit_active_sec,
+        persistent,
+    } = merge_timer(instance_timer, template_timer)?;
+
+    let mut unit = Section::new("Unit");
+    unit.push("Description", format!("{} timer", description));
+
+    let mut timer = Section::new("Timer");
+    if let Some(v) = on_calendar {
+        timer.push("OnCalendar", v);
+    }
+    if let Some(v) = on_boot_sec {
+        timer.push("OnBootSec", v);
+    }
+    if let Some(v) = on_unit_active_sec {
+        timer.push("OnUnitActiveSec", v);
+    }
+    if let Some(v) = persistent {
+        timer.push("Persistent", v);
+    }
+
+    let mut install = Section::new("Install");
+    install.push("WantedBy", "timers.target");
+    Some(UnitFile {
+        sections: vec![unit, timer, install],
+    })
+}
+
+fn resolve_socket(
+    instance_socket: Option<Socket>,
+    template_socket: Option<Socket>,
+    description: &str,
+) -> Option<UnitFile> {
+    let Socket {
+        listen_stream,
+        listen_datagram,
+        socket_user,
+        socket_mode,
+        accept,
+    } = merge_socket(instance_socket, template_socket)?;
+
+    let mut unit = Section::new("Unit");
+    unit.push("Description", format!("{} socket", description));
+
+    let mut socket = Section::new("Socket");
+    if let Some(v) = listen_stream {
+        socket.push("ListenStream", v);
+    }
+    if let Some(v) = listen_datagram {
+        socket.push("ListenDatagram", v);
+    }
+    if let Some(v) = socket_user {
+        socket.push("SocketUser", v);
+    }
+    if let Some(v) = socket_mode {
+        socket.push("SocketMode", v);
+    }
+    if let Some(v) = accept {
+        socket.push("Accept", v);
+    }
+
+    let mut install = Section::new("Install");
+    install.push("WantedBy", "sockets.target");
+    Some(UnitFile {
+        sections: vec![unit, socket, install],
+    })
+}
+
+fn resolve_nspawn(
+    instance_nspawn: Option<Nspawn>,
+    template_nspawn: Option<Nspawn>,
+) -> Option<UnitFile> {
+    let Nspawn {
+        boot,
+        bind,
+        bind_read_only,
+        private_network,
+        virtual_ethernet,
+        bridge,
+        port,
+    } = merge_nspawn(instance_nspawn, template_nspawn)?;
+
+    let mut exec = Section::new("Exec");
+    if let Some(v) = boot {
+        exec.push("Boot", v);
+    }
+
+    let mut files = Section::new("Files");
+    for v in bind.unwrap_or_default() {
+        files.push("Bind", v);
+    }
+    for v in bind_read_only.unwrap_or_default() {
+        files.push("BindReadOnly", v);
+    }
+
+    let mut network = Section::new("Network");
+    if let Some(v) = private_network {
+        network.push("Private", v);
+    }
+    if let Some(v) = virtual_ethernet {
+        network.push("VirtualEthernet", v);
+    }
+    if let Some(v) = bridge {
+        network.push("Bridge", v);
+    }
+    for v in port.unwrap_or_default() {
+        network.push("Port", v);
+    }
+    let sections: Vec<Section> = [exec, files, network]
+        .into_iter()
+        .filter(|s| !s.entries.is_empty())
+        .collect();
+    if sections.is_empty() {
+        return None;
+    }
+    Some(UnitFile { sections })
+}
+
+fn resolve_network(instance: NetworkInstanceDef, template: NetworkTemplateDef) -> UnitFile {
+    let template_match = template.r#match.unwrap_or_default();
+    let mut match_name = template_match.name;
+    let mut match_mac_address = template_match.mac_address;
+
+    if let Some(i) = instance.r#match {
+        if i.name.is_some() {
+            match_name = i.name;
+        }
+        if i.mac_address.is_some() {
+            match_mac_address = i.mac_address;
+        }
+    }
+
+    let template_network = template.network.unwrap_or_default();
+    let mut address = template_network.address;
+    let mut gateway = template_network.gateway;
+    let mut dns = template_network.dns;
+    let mut dhcp = template_network.dhcp;
+    let mut bridge = template_network.bridge;
+
+    if let Some(i) = instance.network {
+        if i.address.is_some() {
+            address = i.address;
+        }
+        if i.gateway.is_some() {
+            gateway = i.gateway;
+        }
+        if i.dns.is_some() {
+            dns = i.dns;
+        }
+        if i.dhcp.is_some() {
+            dhcp = i.dhcp;
+        }
+        if i.bridge.is_some() {
+            bridge = i.bridge;
+        }
+    }
+
+    // an empty [Match] matches every link, so fall back to the instance name
+    // like the netdev does
+    if match_name.is_none() && match_mac_address.is_none() {
+        match_name = Some(instance.name.clone());
+    }
+
+    let mut r#match = Section::new("Match");
+    if let Some(v) = match_name {
+        r#match.push("Name", v);
+    }
+    if let Some(v) = match_mac_address {
+        r#match.push("MACAddress", v);
+    }
+
+    let mut network = Section::new("Network");
+    for v in address.unwrap_or_default() {
+        network.push("Address", v);
+    }
+    if let Some(v) = gateway {
+        network.push("Gateway", v);
+    }
+    for v in dns.unwrap_or_default() {
+        network.push("DNS", v);
+    }
+    if let Some(v) = dhcp {
+        network.push("DHCP", v);
+    }
+    if let Some(v) = bridge {
+        network.push("Bridge", v);
+    }
+    UnitFile {
+        sections: vec![r#match, network],
+    }
+}
+
+fn resolve_netdev(
+    instance_netdev: Option<NetDevSection>,
+    template_netdev: Option<NetDevSection>,
+    default_name: &str,
+) -> Option<UnitFile> {
+    if instance_netdev.is_none() && template_netdev.is_none() {
+        return None;
+    }
+
+    let template_netdev = template_netdev.unwrap_or_default();
+    let mut name = template_netdev.name;
+    let mut kind = template_netdev.kind;
+    let mut description = template_netdev.description;
+
+    if let Some(i) = instance_netdev {
+        if i.name.is_some() {
+            name = i.name;
+        }
+        if i.kind.is_some() {
+            kind = i.kind;
+        }
+        if i.description.is_some() {
+            description = i.description;
+        }
+    }
+
+    let mut netdev = Section::new("NetDev");
+    netdev.push("Name", name.unwrap_or_else(|| default_name.into()));
+    if let Some(v) = kind {
+        netdev.push("Kind", v);
+    }
+    if let Some(v) = description {
+        netdev.push("Description", v);
+    }
+    Some(UnitFile {
+        sections: vec![netdev],
+    })
+}
+
+fn resolve_sysusers(service: &Service, description: &str) -> Option<Vec<String>> {
+    if service.user.is_none() && service.group.is_none() {
+        return None;
+    }
+
+    let mut lines = vec![];
+    if let Some(group) = &service.group {
+        lines.push(format!("g {} -", group));
+    }
+    if let Some(user) = &service.user {
+        lines.push(format!("u {} - \"{}\"", user, description));
+        if let Some(group) = &service.group {
+            if group != user {
+                lines.push(format!("m {} {}", user, group));
+            }
+        }
+    }
+    Some(lines)
+}
+
+/// `value` without its unit specifiers, for files that do not expand them.
+fn strip_specifiers(value: &str) -> String {
+    let mut stripped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => {
+                if chars.next() == Some('%') {
+                    stripped.push('%');
+                }
+            }
+            c => stripped.push(c),
+        }
+    }
+    stripped.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn resolve_tmpfiles(
+    instance_directories: Option<Vec<Directory>>,
+    template_directories: Option<Vec<Directory>>,
+) -> Option<Vec<String>> {
+    let directories = instance_directories.or(template_directories)?;
+
+    let lines = directories
+        .into_iter()
+        .map(|directory| {
+            format!(
+                "d {} {} {} {} -",
+                directory.path,
+                directory.mode.unwrap_or("-".into()),
+                directory.owner.unwrap_or("-".into()),
+                directory.group.unwrap_or("-".into()),
+            )
+        })
+        .collect();
+    Some(lines)
+}
+
+fn resolve_target(target: &Target, unit_names: &[String]) -> UnitFile {
+    let mut unit = Section::new("Unit");
+    unit.push("Description", &target.description);
+    for unit_name in unit_names {
+        unit.push("Wants", format!("{}.service", unit_name));
+    }
+
+    let mut install = Section::new("Install");
+    install.push("WantedBy", &target.wanted_by);
+    UnitFile {
+        sections: vec![unit, install],
+    }
+}
+
+fn resolve_slice(slice: &Slice) -> Result<UnitFile, String> {
+    let mut unit = Section::new("Unit");
+    unit.push("Description", &slice.description);
+
+    let mut section = Section::new("Slice");
+    for (k, v) in [
+        ("CPUWeight", slice.cpu_weight),
+        ("IOWeight", slice.io_weight),
+    ] {
+        if let Some(v) = v {
+            if !(1..=10000).contains(&v) {
+                return Err(format!("{}={} must be between 1 and 10000", k, v));
+            }
+            section.push(k, v);
+        }
+    }
+    for (k, v) in [
+        ("MemoryHigh", &slice.memory_high),
+        ("MemoryMax", &slice.memory_max),
+    ] {
+        if let Some(v) = v {
+            if !is_valid_memory(v) {
+                return Err(format!("{}={} is not a valid memory size", k, v));
+            }
+            section.push(k, v);
+        }
+    }
+    Ok(UnitFile {
+        sections: vec![unit, section],
+    })
+}
+
+fn extend(mut parent: Vec<String>, child: Vec<String>) -> Vec<String> {
+    parent.extend(child);
+    parent
+}
+
+fn extend_map<V>(
+    parent: Option<BTreeMap<String, V>>,
+    child: Option<BTreeMap<String, V>>,
+) -> Option<BTreeMap<String, V>> {
+    match (parent, child) {
+        (Some(mut p), Some(c)) => {
+            p.extend(c);
+            Some(p)
+        }
+        (p, c) => c.or(p),
+    }
+}
+
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Resolves an `include` entry relative to `dir`, expanding `*` and `?` in
+/// its file name.
+fn include_paths(dir: &Path, include: &str) -> Result<Vec<PathBuf>, Error> {
+    let path = dir.join(include);
+    let file_name = path
+        .file_name()
+        .map(|v| v.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !file_name.contains(['*', '?']) {
+        return Ok(vec![path]);
+    }
+    let parent = path.parent().unwrap_or(dir);
+    let pattern: Vec<char> = file_name.chars().collect();
+    let entries =
+        fs::read_dir(parent).map_err(|e| Error::Io(format!("{}: {}", parent.display(), e)))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            let name: Vec<char> = p
+                .file_name()
+                .map(|v| v.to_string_lossy().chars().collect())
+                .unwrap_or_default();
+            p.is_file() && wildcard_match(&pattern, &name)
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Appends the defs, slices and networks of an included file and adds its
+/// named templates.
+fn merge_definitions(
+    base: &mut serde_yaml::Value,
+    other: serde_yaml::Value,
+    path: &Path,
+) -> Result<(), String> {
+    let (Some(base), serde_yaml::Value::Mapping(other)) = (base.as_mapping_mut(), other) else {
+        return Err(format!("{}: expected a mapping", path.display()));
+    };
+    for (k, v) in other {
+        // every file has already been upgraded to the same version
+        if k == "version" {
+            continue;
+        }
+        match (base.get_mut(&k), v) {
+            (Some(serde_yaml::Value::Sequence(b)), serde_yaml::Value::Sequence(v)) => b.extend(v),
+            (Some(serde_yaml::Value::Mapping(b)), serde_yaml::Value::Mapping(v)) => {
+                for (name, t) in v {
+                    if b.contains_key(&name) {
+                        return Err(format!(
+                            "{}: {} is already defined",
+                            path.display(),
+                            scalar_string(&name).unwrap_or_default()
+                        ));
+                    }
+                    b.insert(name, t);
+                }
+            }
+            (None, v) => {
+                base.insert(k, v);
+            }
+            (Some(_), _) => {
+                return Err(format!(
+                    "{}: {} does not match the including file",
+                    path.display(),
+                    scalar_string(&k).unwrap_or_default()
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Upgrades from version i + 1 to i + 2 of the definitions file schema.
+const MIGRATIONS: &[fn(&mut serde_yaml::Value)] = &[];
+
+pub const SCHEMA_VERSION: u64 = MIGRATIONS.len() as u64 + 1;
+
+/// Brings a definitions file up to SCHEMA_VERSION, returning the version it
+/// was written for, or None if it already states SCHEMA_VERSION. Files
+/// without a `version` are version 1.
+fn upgrade_definitions(value: &mut serde_yaml::Value, path: &Path) -> Result<Option<u64>, String> {
+    let version = match value.get("version") {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .filter(|&v| v >= 1)
+            .ok_or_else(|| format!("{}: version must be a positive integer", path.display()))?,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "{}: version {} is newer than the supported version {}",
+            path.display(),
+            version,
+            SCHEMA_VERSION
+        ));
+    }
+    if version == SCHEMA_VERSION && value.get("version").is_some() {
+        return Ok(None);
+    }
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(value);
+    }
+    if let Some(m) = value.as_mapping_mut() {
+        let rest = std::mem::take(m);
+        m.insert("version".into(), SCHEMA_VERSION.into());
+        m.extend(rest.into_iter().filter(|(k, _)| k != "version"));
+    }
+    Ok(Some(version))
+}
+
+fn read_definitions(path: &Path) -> Result<String, Error> {
+    fs::read_to_string(path).map_err(|e| Error::Io(format!("{}: {}", path.display(), e)))
+}
+
+fn parse_definitions(src: &str, path: &Path, format: Format) -> Result<serde_yaml::Value, Error> {
+    let mut value: serde_yaml::Value = match format {
+        Format::Yaml => serde_yaml::from_str(src).map_err(|e| e.to_string()),
+        Format::Json => serde_json::from_str::<serde_json::Value>(src)
+            .map_err(|e| e.to_string())
+            .and_then(|v| serde_yaml::to_value(v).map_err(|e| e.to_string())),
+        Format::Toml => parse_toml(src),
+    }
+    .map_err(|e| Error::Parse(format!("{}: {}", path.display(), e)))?;
+    value
+        .apply_merge()
+        .map_err(|e| Error::Parse(format!("{}: {}", path.display(), e)))?;
+    Ok(value)
+}
+
+/// Rewrites the definitions file at `path` at SCHEMA_VERSION, returning the
+/// version it was written for. Files already at SCHEMA_VERSION are not
+/// touched and give None. Files it includes are left alone.
+pub fn migrate(path: &Path, format: Option<Format>) -> Result<Option<u64>, Error> {
+    let format = format.unwrap_or_else(|| Format::detect(path));
+    let mut value = parse_definitions(&read_definitions(path)?, path, format)?;
+    let version = match upgrade_definitions(&mut value, path).map_err(Error::Parse)? {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    let contents = match format {
+        Format::Yaml => serde_yaml::to_string(&value).map_err(|e| e.to_string()),
+        Format::Json => serde_json::to_string_pretty(&value)
+            .map(|s| s + "\n")
+            .map_err(|e| e.to_string()),
+        Format::Toml => to_toml(&value),
+    }
+    .map_err(|e| Error::Write(format!("{}: {}", path.display(), e)))?;
+    fs::write(path, contents).map_err(|e| Error::Write(format!("{}: {}", path.display(), e)))?;
+    Ok(Some(version))
+}
+
+/// Reads a definitions file along with everything it `include`s. Included
+/// files are always detected by extension.
+fn visit_definitions(path: &Path, seen: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let canonical =
+        fs::canonicalize(path).map_err(|e| Error::Io(format!("{}: {}", path.display(), e)))?;
+    if seen.contains(&canonical) {
+        return Err(Error::Parse(format!(
+            "{} is included more than once",
+            path.display()
+        )));
+    }
+    seen.push(canonical);
+    Ok(())
+}
+
+fn load_definitions(
+    src: &str,
+    path: &Path,
+    format: Format,
+    seen: &mut Vec<PathBuf>,
+    sources: &mut Vec<Source>,
+) -> Result<serde_yaml::Value, Error> {
+    let mut value = parse_definitions(src, path, format)?;
+    upgrade_definitions(&mut value, path).map_err(Error::Parse)?;
+    // top-level x- keys only exist to hold anchors for merge keys
+    if let Some(m) = value.as_mapping_mut() {
+        m.retain(|k, _| !k.as_str().is_some_and(|k| k.starts_with("x-")));
+    }
+
+    let includes = value
+        .as_mapping_mut()
+        .and_then(|m| m.remove("include"))
+        .map(serde_yaml::from_value::<Vec<String>>)
+        .transpose()
+        .map_err(|e| Error::Parse(format!("{}: include: {}", path.display(), e)))?
+        .unwrap_or_default();
+    // recorded before merging so entries line up with the merged sections
+    let shared_src: Rc<str> = src.into();
+    for (section, entries) in value.as_mapping().into_iter().flatten() {
+        let keys: Vec<serde_yaml::Value> = match entries {
+            serde_yaml::Value::Sequence(s) => (0..s.len()).map(serde_yaml::Value::from).collect(),
+            serde_yaml::Value::Mapping(m) => m.keys().cloned().collect(),
+            _ => continue,
+        };
+        for key in keys {
+            sources.push(Source {
+                section: scalar_string(section).unwrap_or_default(),
+                key,
+                path: path.to_path_buf(),
+                format,
+                src: shared_src.clone(),
+            });
+        }
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+    for include in includes {
+        for included in include_paths(dir, &include)? {
+            visit_definitions(&included, seen)?;
+            let src = read_definitions(&included)?;
+            let other =
+                load_definitions(&src, &included, Format::detect(&included), seen, sources)?;
+            merge_definitions(&mut value, other, &included).map_err(Error::Parse)?;
+        }
+    }
+    Ok(value)
+}
+
+fn merge_template_units(unit: TemplateUnit, parent: TemplateUnit) -> TemplateUnit {
+    TemplateUnit {
+        description: unit.description.or(parent.description),
+        requires: extend(parent.requires, unit.requires),
+        after: extend(parent.after, unit.after),
+        before: extend(parent.before, unit.before),
+        wants: extend(parent.wants, unit.wants),
+        conflicts: extend(parent.conflicts, unit.conflicts),
+        upholds: extend(parent.upholds, unit.upholds),
+        on_failure: extend(parent.on_failure, unit.on_failure),
+        on_success: extend(parent.on_success, unit.on_success),
+        documentation: extend(parent.documentation, unit.documentation),
+        propagates_reload_to: extend(parent.propagates_reload_to, unit.propagates_reload_to),
+        reload_propagated_from: extend(parent.reload_propagated_from, unit.reload_propagated_from),
+        requires_mounts_for: extend(parent.requires_mounts_for, unit.requires_mounts_for),
+        start_limit_action: unit.start_limit_action.or(parent.start_limit_action),
+        start_limit_burst: unit.start_limit_burst.or(parent.start_limit_burst),
+        start_limit_interval_sec: unit
+            .start_limit_interval_sec
+            .or(parent.start_limit_interval_sec),
+        default_dependencies: unit.default_dependencies.or(parent.default_dependencies),
+        stop_when_unneeded: unit.stop_when_unneeded.or(parent.stop_when_unneeded),
+        refuse_manual_start: unit.refuse_manual_start.or(parent.refuse_manual_start),
+        refuse_manual_stop: unit.refuse_manual_stop.or(parent.refuse_manual_stop),
+        job_timeout_sec: unit.job_timeout_sec.or(parent.job_timeout_sec),
+        job_timeout_action: unit.job_timeout_action.or(parent.job_timeout_action),
+        success_action: unit.success_action.or(parent.success_action),
+        failure_action: unit.failure_action.or(parent.failure_action),
+        ignore_on_isolate: unit.ignore_on_isolate.or(parent.ignore_on_isolate),
+        collect_mode: unit.collect_mode.or(parent.collect_mode),
+        conditions: extend_map(parent.conditions, unit.conditions),
+        asserts: extend_map(parent.asserts, unit.asserts),
+    }
+}
+
+/// Flattens a template's `Extends` chain so the result no longer refers to
+/// any named template.
+fn resolve_template(
+    template: TemplateServiceDef,
+    templates: &BTreeMap<String, TemplateServiceDef>,
+    seen: &mut Vec<String>,
+) -> Result<TemplateServiceDef, String> {
+    let name = match &template.extends {
+        Some(v) => v.clone(),
+        None => return Ok(template),
+    };
+    if seen.contains(&name) {
+        return Err(format!("template {} is part of an Extends cycle", name));
+    }
+    seen.push(name.clone());
+    let parent = match templates.get(&name) {
+        Some(v) => resolve_template(v.clone(), templates, seen)?,
+        None => return Err(format!("template {} is not defined", name)),
+    };
+
+    Ok(TemplateServiceDef {
+        extends: None,
+        vars: extend_map(parent.vars, template.vars),
+        unit: merge_template_units(template.unit, parent.unit),
+        service: resolve_service(Some(template.service), parent.service)?,
+        install: match (template.install, parent.install) {
+            (Some(i), Some(t)) => Some(merge_install(i, t)),
+            (i, t) => i.or(t),
+        },
+        timer: merge_timer(template.timer, parent.timer),
+        socket: merge_socket(template.socket, parent.socket),
+        nspawn: merge_nspawn(template.nspawn, parent.nspawn),
+        directories: template.directories.or(parent.directories),
+    })
+}
+
+fn native_template_instance(name: &str, template: &TemplateServiceDef) -> InstanceServiceDef {
+    InstanceServiceDef {
+        vars: None,
+        unit: InstanceUnit {
+            name: format!("{}@", name),
+            description: Some(
+                template
+                    .unit
+                    .description
+                    .clone()
+                    .unwrap_or_else(|| format!("{} %I", name)),
+            ),
+            requires: None,
+            after: None,
+            before: None,
+            wants: None,
+            conflicts: None,
+            upholds: None,
+            on_failure: None,
+            on_success: None,
+            documentation: None,
+            propagates_reload_to: None,
+            reload_propagated_from: None,
+            inherit_requires: true,
+            inherit_after: true,
+            inherit_before: true,
+            inherit_wants: true,
+            inherit_conflicts: true,
+            inherit_upholds: true,
+            inherit_on_failure: true,
+            inherit_on_success: true,
+            inherit_documentation: true,
+            inherit_propagates_reload_to: true,
+            inherit_reload_propagated_from: true,
+            requires_mounts_for: None,
+            inherit_requires_mounts_for: true,
+            part_of: None,
+            merge: None,
+            dedupe: true,
+            start_limit_action: None,
+            start_limit_burst: None,
+            start_limit_interval_sec: None,
+            default_dependencies: None,
+            stop_when_unneeded: None,
+            refuse_manual_start: None,
+            refuse_manual_stop: None,
+            job_timeout_sec: None,
+            job_timeout_action: None,
+            success_action: None,
+            failure_action: None,
+            ignore_on_isolate: None,
+            collect_mode: None,
+            conditions: None,
+            asserts: None,
+        },
+        service: None,
+        install: None,
+        timer: None,
+        socket: None,
+        nspawn: None,
+        directories: None,
+        drop_in: false,
+        enabled: true,
+        inherit_install: false,
+        inherit_service: true,
+    }
+}
+
+/// Lists the fields of a native template instance other than Name,
+/// Description and Enabled, which it cannot use since every instance shares
+/// the template's unit.
+fn ignored_native_fields(instance: &InstanceServiceDef) -> Vec<String> {
+    let mut unit = serde_yaml::Mapping::new();
+    unit.insert("Name".into(), instance.unit.name.clone().into());
+    if let Some(v) = &instance.unit.description {
+        unit.insert("Description".into(), v.clone().into());
+    }
+    let mut bare = serde_yaml::Mapping::new();
+    bare.insert("Unit".into(), unit.into());
+    bare.insert("Enabled".into(), instance.enabled.into());
+    let bare = serde_yaml::from_value::<InstanceServiceDef>(bare.into())
+        .and_then(|v| serde_yaml::to_value(v).map_err(serde::de::Error::custom));
+    let (Ok(serde_yaml::Value::Mapping(bare)), Ok(serde_yaml::Value::Mapping(set))) =
+        (bare, serde_yaml::to_value(instance))
+    else {
+        return vec![];
+    };
+
+    let mut fields = vec![];
+    for (k, v) in &set {
+        let key = scalar_string(k).unwrap_or_default();
+        match (v, bare.get(k)) {
+            (serde_yaml::Value::Mapping(v), Some(serde_yaml::Value::Mapping(b))) => {
+                for (k, _) in v.iter().filter(|(k, v)| b.get(k) != Some(v)) {
+                    fields.push(format!("{}.{}", key, scalar_string(k).unwrap_or_default()));
+                }
+            }
+            (v, b) if b != Some(v) => fields.push(key),
+            _ => {}
+        }
+    }
+    fields
+}
+
+fn map_strings(value: &mut serde_yaml::Value, f: &dyn Fn(&str) -> String) {
+    match value {
+        serde_yaml::Value::String(s) => *s = f(s),
+        serde_yaml::Value::Sequence(seq) => {
+            for v in seq {
+                map_strings(v, f);
+            }
+        }
+        serde_yaml::Value::Mapping(m) => {
+            for (_, v) in m.iter_mut() {
+                map_strings(v, f);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replaces `{{ name }}` placeholders in every string below `value`.
+/// Placeholders without a matching variable are left as written, unless
+/// `strict` is set and they look like a variable name.
+fn interpolate(
+    value: &mut serde_yaml::Value,
+    vars: &BTreeMap<String, String>,
+    strict: bool,
+) -> Result<(), String> {
+    let unknown = std::cell::RefCell::new(None);
+    map_strings(value, &|s| {
+        let mut out = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}") else {
+                break;
+            };
+            let key = rest[start + 2..start + end].trim();
+            out += &rest[..start];
+            match vars.get(key) {
+                Some(v) => out += v,
+                None => {
+                    let is_var = !key.is_empty()
+                        && key
+                            .chars()
+                            .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+                    if strict && is_var {
+                        unknown.borrow_mut().get_or_insert_with(|| key.to_string());
+                    }
+                    out += &rest[start..start + end + 2];
+                }
+            }
+            rest = &rest[start + end + 2..];
+        }
+        out + rest
+    });
+    match unknown.into_inner() {
+        Some(key) if vars.is_empty() => Err(format!(
+            "unknown variable `{}`, there are no variables",
+            key
+        )),
+        Some(key) => {
+            let expected: Vec<String> = vars.keys().map(|k| format!("`{}`", k)).collect();
+            Err(suggest_field(&format!(
+                "unknown variable `{}`, expected one of {}",
+                key,
+                expected.join(", ")
+            )))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` from the environment. Unset
+/// variables without a default are left for systemd to expand at runtime,
+/// and `$${VAR}` always produces a literal `${VAR}`.
+fn expand_env(value: &mut serde_yaml::Value) {
+    map_strings(value, &|s| {
+        let mut out = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("${") {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            let expr = &rest[start..start + end + 1];
+            if rest[..start].ends_with('$') {
+                out += &rest[..start - 1];
+                out += expr;
+            } else {
+                out += &rest[..start];
+                let inner = &expr[2..expr.len() - 1];
+                let (name, default) = match inner.split_once(":-") {
+                    Some((n, d)) => (n, Some(d)),
+                    None => (inner, None),
+                };
+                // like the shell, `:-` also replaces a variable that is set but empty
+                let value = std::env::var(name)
+                    .ok()
+                    .filter(|v| default.is_none() || !v.is_empty());
+                match (value, default) {
+                    (Some(v), _) => out += &v,
+                    (None, Some(d)) => out += d,
+                    (None, None) => out += expr,
+                }
+            }
+            rest = &rest[start + end + 1..];
+        }
+        out + rest
+    });
+}
+
+/// Deep-merges `overlay` onto `base`: mappings merge key by key, anything
+/// else in the overlay replaces the base value.
+fn overlay_value(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (k, v) in overlay {
+                match base.get_mut(&k) {
+                    Some(b) => overlay_value(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Removes every def's `profiles` and applies the selected one on top of the
+/// def.
+fn apply_profiles(value: &mut serde_yaml::Value, profile: Option<&str>) -> Result<(), String> {
+    let mut found = false;
+    if let Some(defs) = value.get_mut("defs").and_then(|v| v.as_sequence_mut()) {
+        for def in defs {
+            let profiles = def.as_mapping_mut().and_then(|m| m.remove("profiles"));
+            let overlay = match (profiles, profile) {
+                (Some(mut p), Some(name)) => p.as_mapping_mut().and_then(|m| m.remove(name)),
+                _ => None,
+            };
+            if let Some(overlay) = overlay {
+                found = true;
+                overlay_value(def, overlay);
+            }
+        }
+    }
+    match (profile, found) {
+        (Some(name), false) => Err(format!("profile {} is not defined by any def", name)),
+        _ => Ok(()),
+    }
+}
+
+/// A slices, networks, templates or defs entry and the file it was read from.
+struct Source {
+    section: String,
+    key: serde_yaml::Value,
+    path: PathBuf,
+    format: Format,
+    src: Rc<str>,
+}
+
+const SEEK_FOUND: &str = "reached the path being located";
+
+/// Walks a definitions file down `path` and fails there, so that the
+/// deserializer reports the position.
+struct Seek<'a>(&'a [serde_yaml::Value]);
+
+/// Rejects whatever it is given, with an error naming SEEK_FOUND.
+struct Found;
+
+impl<'de> serde::de::Visitor<'de> for Found {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(SEEK_FOUND)
+    }
+}
+
+impl<'de> serde::de::DeserializeSeed<'de> for Seek<'_> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        if self.0.is_empty() {
+            return deserializer.deserialize_any(Found);
+        }
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for Seek<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("any value")
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<serde_yaml::Value>()? {
+            if key == self.0[0] {
+                return map.next_value_seed(Seek(&self.0[1..]));
+            }
+            map.next_value::<serde::de::IgnoredAny>()?;
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let Some(index) = self.0[0].as_u64() else {
+            return Ok(());
+        };
+        for _ in 0..index {
+            if seq.next_element::<serde::de::IgnoredAny>()?.is_none() {
+                return Ok(());
+            }
+        }
+        seq.next_element_seed(Seek(&self.0[1..])).map(|_| ())
+    }
+}
+
+/// Line and column of the value at `path` in a definitions file, or of the
+/// closest ancestor the file has.
+fn locate(src: &str, format: Format, path: &[serde_yaml::Value]) -> Option<(usize, usize)> {
+    use serde::de::DeserializeSeed;
+    (1..=path.len()).rev().find_map(|n| match format {
+        Format::Yaml => {
+            let e = Seek(&path[..n])
+                .deserialize(serde_yaml::Deserializer::from_str(src))
+                .err()?;
+            let location = e
+                .location()
+                .filter(|_| e.to_string().contains(SEEK_FOUND))?;
+            Some((location.line(), location.column()))
+        }
+        Format::Json => {
+            let e = Seek(&path[..n])
+                .deserialize(&mut serde_json::Deserializer::from_str(src))
+                .err()?;
+            e.to_string()
+                .contains(SEEK_FOUND)
+                .then(|| (e.line(), e.column()))
+        }
+        Format::Toml => {
+            let (_, locations) = parse_toml_located(src).ok()?;
+            locations
+                .into_iter()
+                .find(|(p, _)| p[..] == path[..n])
+                .map(|(_, position)| position)
+        }
+    })
+}
+
+fn value_at_mut<'a>(
+    value: &'a mut serde_yaml::Value,
+    path: &[serde_yaml::Value],
+) -> Option<&'a mut serde_yaml::Value> {
+    path.iter().try_fold(value, |v, k| match v {
+        serde_yaml::Value::Mapping(m) => m.get_mut(k),
+        serde_yaml::Value::Sequence(s) => s.get_mut(k.as_u64()? as usize),
+        _ => None,
+    })
+}
+
+fn deserialize_error<T: serde::de::DeserializeOwned>(value: &serde_yaml::Value) -> Option<String> {
+    T::deserialize(value).err().map(|e| e.to_string())
+}
+
+/// Narrows `error` down to the key or index under `value` it comes from, by
+/// finding what has to be removed for `check` to report something else.
+fn error_path(
+    value: &serde_yaml::Value,
+    error: &str,
+    check: fn(&serde_yaml::Value) -> Option<String>,
+) -> Vec<serde_yaml::Value> {
+    let mut path = vec![];
+    let mut scratch = value.clone();
+    loop {
+        let keys: Vec<serde_yaml::Value> = match value_at_mut(&mut scratch, &path) {
+            Some(serde_yaml::Value::Mapping(m)) => m.keys().cloned().collect(),
+            Some(serde_yaml::Value::Sequence(s)) => {
+                (0..s.len()).map(serde_yaml::Value::from).collect()
+            }
+            _ => return path,
+        };
+        let culprit = keys.into_iter().find(|k| {
+            let mut v = value.clone();
+            match value_at_mut(&mut v, &path) {
+                Some(serde_yaml::Value::Mapping(m)) => {
+                    m.remove(k);
+                }
+                Some(serde_yaml::Value::Sequence(s)) => {
+                    s.remove(k.as_u64().unwrap() as usize);
+                }
+                _ => {}
+            }
+            check(&v).as_deref() != Some(error)
+        });
+        match culprit {
+            Some(k) => path.push(k),
+            None => return path,
+        }
+    }
+}
+
+/// Explains why `value` is not a valid DefinitionFile: which entry is wrong,
+/// where it is in which file and what the problem is.
+fn definitions_error(
+    value: &serde_yaml::Value,
+    error: &str,
+    sources: &[Source],
+    instance_origins: &[Vec<usize>],
+    root: (&Path, Format, &str),
+) -> String {
+    type Check = fn(&serde_yaml::Value) -> Option<String>;
+    let sections: [(&str, Check); 4] = [
+        ("slices", deserialize_error::<Slice>),
+        (
+            "networks",
+            deserialize_error::<NetworkTemplatesAndInstances>,
+        ),
+        ("templates", deserialize_error::<TemplateServiceDef>),
+        ("defs", deserialize_error::<TemplatesAndInstances>),
+    ];
+    let failing = sections.into_iter().find_map(|(section, check)| {
+        let entries: Vec<(serde_yaml::Value, &serde_yaml::Value)> = match value.get(section) {
+            Some(serde_yaml::Value::Sequence(s)) => s
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (serde_yaml::Value::from(i), v))
+                .collect(),
+            Some(serde_yaml::Value::Mapping(m)) => m.iter().map(|(k, v)| (k.clone(), v)).collect(),
+            _ => vec![],
+        };
+        entries
+            .into_iter()
+            .find_map(|(key, entry)| check(entry).map(|e| (section, key, entry, check, e)))
+    });
+
+    let Some((section, key, mut entry, mut check, mut error)) = failing else {
+        let path = error_path(value, error, deserialize_error::<DefinitionFile>);
+        let location = locate(root.2, root.1, &path);
+        return format!(
+            "{}{}: {}",
+            root.0.display(),
+            location
+                .map(|(l, c)| format!(":{}:{}", l, c))
+                .unwrap_or_default(),
+            suggest_field(error)
+        );
+    };
+    let merged_index = key.as_u64().map(|i| i as usize);
+    let source = match merged_index {
+        Some(i) => sources.iter().filter(|s| s.section == section).nth(i),
+        None => sources
+            .iter()
+            .find(|s| s.section == section && s.key == key),
+    };
+    let mut context = match merged_index {
+        Some(i) => format!("{}[{}]", section, i),
+        None => format!("{}.{}", section, scalar_string(&key).unwrap_or_default()),
+    };
+    let mut path = vec![
+        section.into(),
+        source.map_or(key.clone(), |s| s.key.clone()),
+    ];
+
+    // narrow a failing def down to its template or one of its instances
+    if let (Some(i), "defs") = (merged_index, section) {
+        let template = entry
+            .get("template")
+            .filter(|t| deserialize_error::<TemplateServiceDef>(t).is_some());
+        let instance = entry
+            .get("instances")
+            .and_then(|v| v.as_sequence())
+            .into_iter()
+            .flatten()
+            .enumerate()
+            .find(|(_, v)| deserialize_error::<InstanceServiceDef>(v).is_some());
+        if let Some(t) = template {
+            entry = t;
+            check = deserialize_error::<TemplateServiceDef>;
+            context += ".template";
+            path.push("template".into());
+        } else if let Some((j, v)) = instance {
+            entry = v;
+            check = deserialize_error::<InstanceServiceDef>;
+            context += &format!(".instances[{}]", j);
+            let origin = instance_origins
+                .get(i)
+                .and_then(|o| o.get(j))
+                .copied()
+                .unwrap_or(j);
+            path.extend(["instances".into(), origin.into()]);
+        }
+        error = check(entry).unwrap_or(error);
+    }
+    let name = entry
+        .get("Unit")
+        .and_then(|u| u.get("Name"))
+        .or_else(|| entry.get("Name"))
+        .and_then(scalar_string);
+    if let Some(name) = name {
+        context += &format!(" ({})", name);
+    }
+
+    path.extend(error_path(entry, &error, check));
+    let (file, format, src) = source.map_or(root, |s| (s.path.as_path(), s.format, &s.src));
+    let location = locate(src, format, &path);
+    format!(
+        "{}{}: {}: {}",
+        file.display(),
+        location
+            .map(|(l, c)| format!(":{}:{}", l, c))
+            .unwrap_or_default(),
+        context,
+        suggest_field(&error)
+    )
+}
+
+/// Edits needed to turn `a` into `b`, counting swapping two adjacent
+/// characters as one.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// Swaps serde's list of expected fields in an unknown field error for the
+/// closest one, when it is close enough to be a typo.
+fn suggest_field(msg: &str) -> String {
+    let Some((start, kind)) = ["field", "variable"]
+        .into_iter()
+        .find_map(|kind| Some((msg.find(&format!("unknown {} `", kind))?, kind)))
+    else {
+        return msg.to_string();
+    };
+    let Some((field, expected)) = msg[start + kind.len() + 10..].split_once('`') else {
+        return msg.to_string();
+    };
+    let distance = |c: &&str| edit_distance(&field.to_lowercase(), &c.to_lowercase());
+    let closest = expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .min_by_key(distance)
+        .filter(|c| distance(c) <= (field.len() / 3).max(1));
+    match closest {
+        Some(c) => format!(
+            "{}unknown {} `{}`, did you mean `{}`?",
+            &msg[..start],
+            kind,
+            field,
+            c
+        ),
+        None => msg.to_string(),
+    }
+}
+
+fn scalar_string(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(v) => Some(v.clone()),
+        serde_yaml::Value::Number(v) => Some(v.to_string()),
+        serde_yaml::Value::Bool(v) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// Reads one `Matrix` axis, either a list of scalars or a `a..b`/`a..=b`
+/// integer range.
+fn matrix_axis(name: &str, value: &serde_yaml::Value) -> Result<Vec<String>, String> {
+    if let serde_yaml::Value::Sequence(seq) = value {
+        return seq
+            .iter()
+            .map(|v| scalar_string(v).ok_or(format!("Matrix {} must only contain scalars", name)))
+            .collect();
+    }
+    let range = scalar_string(value).unwrap_or_default();
+    let (start, end, inclusive) = match range.split_once("..") {
+        Some((s, e)) => match e.strip_prefix('=') {
+            Some(e) => (s, e, true),
+            None => (s, e, false),
+        },
+        None => return Err(format!("Matrix {} must be a list or a range", name)),
+    };
+    let (Ok(start), Ok(end)) = (start.trim().parse::<i64>(), end.trim().parse::<i64>()) else {
+        return Err(format!("Matrix {}={} is not a valid range", name, range));
+    };
+    if start > end || (start == end && !inclusive) {
+        return Err(format!("Matrix {}={} is an empty range", name, range));
+    }
+    let values: Vec<String> = match inclusive {
+        true => (start..=end).map(|v| v.to_string()).collect(),
+        false => (start..end).map(|v| v.to_string()).collect(),
+    };
+    Ok(values)
+}
+
+/// Expands every instance carrying a `Matrix` into one instance per
+/// combination of its axes, interpolating the axis values.
+/// Returns, for each def, the index each instance had before expansion.
+fn expand_matrices(value: &mut serde_yaml::Value) -> Result<Vec<Vec<usize>>, String> {
+    let Some(defs) = value.get_mut("defs").and_then(|v| v.as_sequence_mut()) else {
+        return Ok(vec![]);
+    };
+    let mut origins = vec![];
+    for def in defs {
+        let mut origin = vec![];
+        let Some(instances) = def.get_mut("instances").and_then(|v| v.as_sequence_mut()) else {
+            origins.push(origin);
+            continue;
+        };
+        let mut expanded = vec![];
+        for (i, mut instance) in instances.drain(..).enumerate() {
+            let matrix = match instance.as_mapping_mut().and_then(|m| m.remove("Matrix")) {
+                Some(serde_yaml::Value::Mapping(m)) => m,
+                Some(_) => return Err("Matrix must be a mapping of variable to values".into()),
+                None => {
+                    expanded.push(instance);
+                    origin.push(i);
+                    continue;
+                }
+            };
+            let mut combinations = vec![BTreeMap::new()];
+            for (k, v) in &matrix {
+                let name = scalar_string(k).unwrap_or_default();
+                let axis = matrix_axis(&name, v)?;
+                let mut next = vec![];
+                for c in &combinations {
+                    for a in &axis {
+                        let mut c: BTreeMap<String, String> = c.clone();
+                        c.insert(name.clone(), a.clone());
+                        next.push(c);
+                    }
+                }
+                combinations = next;
+            }
+            let mut names = BTreeSet::new();
+            for vars in combinations {
+                let mut v = instance.clone();
+                interpolate(&mut v, &vars, false)?;
+                if let Some(m) = v.as_mapping_mut() {
+                    let instance_vars = m
+                        .entry("Vars".into())
+                        .or_insert_with(|| serde_yaml::Mapping::new().into());
+                    if let Some(iv) = instance_vars.as_mapping_mut() {
+                        for (k, a) in &vars {
+                            iv.entry(k.as_str().into()).or_insert(a.as_str().into());
+                        }
+                    }
+                }
+                let name = v
+                    .get("Unit")
+                    .and_then(|u| u.get("Name"))
+                    .and_then(scalar_string)
+                    .unwrap_or_default();
+                if !names.insert(name.clone()) {
+                    return Err(format!(
+                        "Matrix expands to duplicate instance name {}, use a variable in Name",
+                        name
+                    ));
+                }
+                expanded.push(v);
+                origin.push(i);
+            }
+        }
+        *instances = expanded;
+        origins.push(origin);
+    }
+    Ok(origins)
+}
+
+fn string_map(value: Option<&serde_yaml::Value>) -> BTreeMap<String, String> {
+    value
+        .and_then(|v| v.as_mapping())
+        .into_iter()
+        .flatten()
+        .filter_map(|(k, v)| Some((scalar_string(k)?, scalar_string(v)?)))
+        .collect()
+}
+
+/// Interpolates each instance with the template's `Vars`, its own `Vars`, the
+/// global variables, which override both, and its `name`.
+fn interpolate_instances(
+    value: &mut serde_yaml::Value,
+    global_vars: &BTreeMap<String, String>,
+) -> Result<(), String> {
+    let Some(defs) = value.get_mut("defs").and_then(|v| v.as_sequence_mut()) else {
+        return Ok(());
+    };
+    for def in defs {
+        let template_vars = string_map(def.get("template").and_then(|t| t.get("Vars")));
+        let native = def.get("native_template").is_some();
+        let Some(instances) = def.get_mut("instances").and_then(|v| v.as_sequence_mut()) else {
+            continue;
+        };
+        for instance in instances {
+            let mut vars = template_vars.clone();
+            vars.extend(string_map(instance.get("Vars")));
+            vars.extend(global_vars.clone());
+            interpolate(instance, &vars, false)?;
+            let name = match native {
+                true => Some("%i".to_string()),
+                false => instance
+                    .get("Unit")
+                    .and_then(|u| u.get("Name"))
+                    .and_then(scalar_string),
+            };
+            if let Some(name) = &name {
+                vars.insert("name".into(), name.clone());
+            }
+            interpolate(instance, &vars, true).map_err(|e| match name {
+                Some(name) => format!("{}: {}", name, e),
+                None => e,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn instance_vars(
+    global_vars: &BTreeMap<String, String>,
+    template: &TemplateServiceDef,
+    instance: &InstanceServiceDef,
+    native: bool,
+) -> BTreeMap<String, String> {
+    let mut vars = template.vars.clone().unwrap_or_default();
+    vars.extend(instance.vars.clone().unwrap_or_default());
+    vars.extend(global_vars.clone());
+    let name = match native {
+        true => "%i".to_string(),
+        false => instance.unit.name.clone(),
+    };
+    vars.insert("name".into(), name);
+    vars
+}
+
+fn interpolate_template(
+    template: &TemplateServiceDef,
+    vars: &BTreeMap<String, String>,
+) -> Result<TemplateServiceDef, String> {
+    let mut value = serde_yaml::to_value(template).map_err(|e| e.to_string())?;
+    interpolate(&mut value, vars, true)?;
+    serde_yaml::from_value(value).map_err(|e| e.to_string())
+}
+
+/// A step from a type into one of its parts.
+#[derive(Clone, Copy)]
+enum Step {
+    Field(usize),
+    Some,
+    Item,
+    Value,
+}
+
+/// What a type asked its deserializer for.
+#[derive(Debug)]
+enum Shape {
+    Bool,
+    Integer { unsigned: bool },
+    Number,
+    String,
+    Option,
+    Seq,
+    Map,
+    Struct(&'static str, &'static [&'static str]),
+    Enum(&'static [&'static str]),
+    Any,
+}
+
+#[derive(Debug)]
+enum Traced {
+    /// What the type asked for at the end of the path.
+    Shape(Shape),
+    /// The type took the probe at the end of the path.
+    Accepted,
+    Failed(String),
+}
+
+impl core::fmt::Display for Traced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for Traced {}
+
+impl serde::de::Error for Traced {
+    fn custom<T: core::fmt::Display>(msg: T) -> Self {
+        Traced::Failed(msg.to_string())
+    }
+}
+
+/// Deserializer that follows `path` into a type and then fails with the
+/// shape of whatever the type asks for there, or, given a probe, hands the
+/// type the probe there instead. Untagged enums and anything else that
+/// deserializes ahead of time come out as Shape::Any.
+#[derive(Clone, Copy)]
+struct Tracer<'a> {
+    path: &'a [Step],
+    probe: Option<&'a serde_yaml::Value>,
+}
+
+impl<'a> Tracer<'a> {
+    fn step(self, path: &'a [Step]) -> Self {
+        Tracer { path, ..self }
+    }
+
+    /// The probe, once the end of the path is reached.
+    fn probe(self) -> Option<serde_yaml::Value> {
+        self.probe.filter(|_| self.path.is_empty()).cloned()
+    }
+
+    fn leaf(self, shape: Shape) -> Traced {
+        match self.path.is_empty() {
+            true => Traced::Shape(shape),
+            false => Traced::Failed(format!("no step into {:?}", shape)),
+        }
+    }
+}
+
+struct TraceEntry<'a> {
+    key: Option<&'static str>,
+    value: Tracer<'a>,
+}
+
+impl<'de> serde::de::MapAccess<'de> for TraceEntry<'_> {
+    type Error = Traced;
+
+    fn next_key_seed<K: serde::de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Traced> {
+        match self.key.take() {
+            Some(key) => seed
+                .deserialize(serde::de::IntoDeserializer::into_deserializer(key))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: serde::de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Traced> {
+        // anything after the path is reached only hides whether it was
+        seed.deserialize(self.value)
+            .and_then(|_| Err(Traced::Accepted))
+    }
+}
+
+struct TraceItem<'a>(Option<Tracer<'a>>);
+
+impl<'de> serde::de::SeqAccess<'de> for TraceItem<'_> {
+    type Error = Traced;
+
+    fn next_element_seed<T: serde::de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Traced> {
+        match self.0.take() {
+            Some(t) => seed.deserialize(t).and_then(|_| Err(Traced::Accepted)),
+            None => Ok(None),
+        }
+    }
+}
+
+macro_rules! trace_leaves {
+    ($($method:ident => $shape:expr),* $(,)?) => {
+        $(
+            fn $method<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Traced> {
+                match self.probe() {
+                    Some(probe) => probe.$method(visitor).map_err(serde::de::Error::custom),
+                    None => Err(self.leaf($shape)),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> serde::Deserializer<'de> for Tracer<'_> {
+    type Error = Traced;
+
+    trace_leaves! {
+        deserialize_any => Shape::Any,
+        deserialize_bool => Shape::Bool,
+        deserialize_i8 => Shape::Integer { unsigned: false },
+        deserialize_i16 => Shape::Integer { unsigned: false },
+        deserialize_i32 => Shape::Integer { unsigned: false },
+        deserialize_i64 => Shape::Integer { unsigned: false },
+        deserialize_u8 => Shape::Integer { unsigned: true },
+        deserialize_u16 => Shape::Integer { unsigned: true },
+        deserialize_u32 => Shape::Integer { unsigned: true },
+        deserialize_u64 => Shape::Integer { unsigned: true },
+        deserialize_f32 => Shape::Number,
+        deserialize_f64 => Shape::Number,
+        deserialize_char => Shape::String,
+        deserialize_str => Shape::String,
+        deserialize_string => Shape::String,
+        deserialize_bytes => Shape::Any,
+        deserialize_byte_buf => Shape::Any,
+        deserialize_unit => Shape::Any,
+        deserialize_identifier => Shape::String,
+        deserialize_ignored_any => Shape::Any,
+    }
+
+    fn deserialize_option<V: serde::de::Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, Traced> {
+        if let Some(probe) = self.probe() {
+            return probe
+                .deserialize_option(visitor)
+                .map_err(serde::de::Error::custom);
+        }
+        match self.path.split_first() {
+            None => Err(self.leaf(Shape::Option)),
+            Some((Step::Some, path)) => visitor.visit_some(self.step(path)),
+            Some(_) => Err(self.leaf(Shape::Option)),
+        }
+    }
+
+    fn deserialize_seq<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Traced> {
+        if let Some(probe) = self.probe() {
+            return probe
+                .deserialize_seq(visitor)
+                .map_err(serde::de::Error::custom);
+        }
+        match self.path.split_first() {
+            None => Err(self.leaf(Shape::Seq)),
+            Some((Step::Item, path)) => visitor.visit_seq(TraceItem(Some(self.step(path)))),
+            Some(_) => Err(self.leaf(Shape::Seq)),
+        }
+    }
+
+    fn deserialize_map<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Traced> {
+        if let Some(probe) = self.probe() {
+            return probe
+                .deserialize_map(visitor)
+                .map_err(serde::de::Error::custom);
+        }
+        match self.path.split_first() {
+            None => Err(self.leaf(Shape::Map)),
+            Some((Step::Value, path)) => visitor.visit_map(TraceEntry {
+                key: Some("key"),
+                value: self.step(path),
+            }),
+            Some(_) => Err(self.leaf(Shape::Map)),
+        }
+    }
+
+    fn deserialize_struct<V: serde::de::Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Traced> {
+        if let Some(probe) = self.probe() {
+            return probe
+                .deserialize_struct(name, fields, visitor)
+                .map_err(serde::de::Error::custom);
+        }
+        match self.path.split_first() {
+            None => Err(self.leaf(Shape::Struct(name, fields))),
+            Some((Step::Field(i), path)) => visitor.visit_map(TraceEntry {
+                key: Some(fields[*i]),
+                value: self.step(path),
+            }),
+            Some(_) => Err(self.leaf(Shape::Struct(name, fields))),
+        }
+    }
+
+    fn deserialize_enum<V: serde::de::Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Traced> {
+        match self.probe() {
+            Some(probe) => probe
+                .deserialize_enum(name, variants, visitor)
+                .map_err(serde::de::Error::custom),
+            None => Err(self.leaf(Shape::Enum(variants))),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: serde::de::Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Traced> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_unit_struct<V: serde::de::Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Traced> {
+        match self.probe() {
+            Some(probe) => probe
+                .deserialize_unit_struct(name, visitor)
+                .map_err(serde::de::Error::custom),
+            None => Err(self.leaf(Shape::Any)),
+        }
+    }
+
+    fn deserialize_tuple<V: serde::de::Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Traced> {
+        match self.probe() {
+            Some(probe) => probe
+                .deserialize_tuple(len, visitor)
+                .map_err(serde::de::Error::custom),
+            None => Err(self.leaf(Shape::Seq)),
+        }
+    }
+
+    fn deserialize_tuple_struct<V: serde::de::Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Traced> {
+        match self.probe() {
+            Some(probe) => probe
+                .deserialize_tuple_struct(name, len, visitor)
+                .map_err(serde::de::Error::custom),
+            None => Err(self.leaf(Shape::Seq)),
+        }
+    }
+}
+
+/// What path_schema builds up as it goes.
+#[derive(Default)]
+struct SchemaDefs {
+    /// Struct schemas by name.
+    schemas: serde_json::Map<String, serde_json::Value>,
+    /// A value each struct accepts, made of its required fields.
+    samples: BTreeMap<&'static str, serde_yaml::Value>,
+    /// Values to try where tracing stops, with the schema each stands for.
+    probes: Vec<(serde_yaml::Value, serde_json::Value)>,
+}
+
+/// Runs `T` through a Tracer along `path`, handing it `probe` there.
+fn trace<T: serde::de::DeserializeOwned>(
+    path: &[Step],
+    probe: Option<&serde_yaml::Value>,
+) -> Traced {
+    match T::deserialize(Tracer { path, probe }) {
+        Ok(_) => Traced::Accepted,
+        Err(traced) => traced,
+    }
+}
+
+/// A value that deserializes as `schema`.
+fn schema_sample(
+    schema: &serde_json::Value,
+    samples: &BTreeMap<&'static str, serde_yaml::Value>,
+) -> serde_yaml::Value {
+    if let Some(name) = schema["$ref"].as_str() {
+        let name = name.trim_start_matches("#/$defs/");
+        return samples.get(name).cloned().unwrap_or_default();
+    }
+    if let Some(first) = schema["anyOf"].get(0) {
+        return schema_sample(first, samples);
+    }
+    if let Some(first) = schema["enum"].get(0).and_then(|v| v.as_str()) {
+        return first.into();
+    }
+    match schema["type"].as_str() {
+        Some("boolean") => true.into(),
+        Some("integer") => 1.into(),
+        Some("number") => 1.0.into(),
+        Some("string") => "x".into(),
+        Some("array") => serde_yaml::Value::Sequence(vec![]),
+        Some("object") => serde_yaml::Value::Mapping(serde_yaml::Mapping::new()),
+        _ => serde_yaml::Value::Null,
+    }
+}
+
+/// `schemas` as one schema.
+fn any_of(mut schemas: Vec<serde_json::Value>) -> serde_json::Value {
+    match schemas.len() {
+        0 => serde_json::json!({}),
+        1 => schemas.remove(0),
+        _ => serde_json::json!({"anyOf": schemas}),
+    }
+}
+
+/// The probes `T` takes at the end of `path`, at most one of each type.
+fn probe_schemas<T: serde::de::DeserializeOwned>(
+    path: &[Step],
+    probes: &[(serde_yaml::Value, serde_json::Value)],
+    wrap: fn(serde_yaml::Value) -> serde_yaml::Value,
+) -> Vec<serde_json::Value> {
+    let mut schemas: Vec<serde_json::Value> = Vec::new();
+    for (probe, schema) in probes {
+        if schemas
+            .iter()
+            .any(|s| s.get("type").is_some() && s.get("type") == schema.get("type"))
+        {
+            continue;
+        }
+        if let Traced::Accepted = trace::<T>(path, Some(&wrap(probe.clone()))) {
+            schemas.push(schema.clone());
+        }
+    }
+    schemas
+}
+
+fn step_schema<T: serde::de::DeserializeOwned>(
+    path: &mut Vec<Step>,
+    step: Step,
+    defs: &mut SchemaDefs,
+) -> serde_json::Value {
+    path.push(step);
+    let schema = path_schema::<T>(path, defs);
+    path.pop();
+    schema
+}
+
+/// Schema for the part of `T` at `path`, with structs added to `defs` and
+/// referenced by name.
+fn path_schema<T: serde::de::DeserializeOwned>(
+    path: &mut Vec<Step>,
+    defs: &mut SchemaDefs,
+) -> serde_json::Value {
+    let shape = match trace::<T>(path, None) {
+        Traced::Shape(shape) => shape,
+        _ => Shape::Any,
+    };
+    match shape {
+        Shape::Bool => serde_json::json!({"type": "boolean"}),
+        Shape::Integer { unsigned: true } => serde_json::json!({"type": "integer", "minimum": 0}),
+        Shape::Integer { unsigned: false } => serde_json::json!({"type": "integer"}),
+        Shape::Number => serde_json::json!({"type": "number"}),
+        Shape::String => serde_json::json!({"type": "string"}),
+        Shape::Option => step_schema::<T>(path, Step::Some, defs),
+        Shape::Seq => serde_json::json!({
+            "type": "array",
+            "items": step_schema::<T>(path, Step::Item, defs),
+        }),
+        Shape::Map => serde_json::json!({
+            "type": "object",
+            "additionalProperties": step_schema::<T>(path, Step::Value, defs),
+        }),
+        Shape::Enum(variants) => serde_json::json!({"enum": variants}),
+        Shape::Struct(name, fields) => {
+            if !defs.schemas.contains_key(name) {
+                // placeholder so recursive types stop here
+                defs.schemas
+                    .insert(name.to_string(), serde_json::Value::Null);
+                let mut properties = serde_json::Map::new();
+                for (i, field) in fields.iter().enumerate() {
+                    properties.insert(
+                        field.to_string(),
+                        step_schema::<T>(path, Step::Field(i), defs),
+                    );
+                }
+                // serde reports the missing fields one at a time
+                let mut sample = serde_yaml::Mapping::new();
+                let mut required = Vec::new();
+                while let Traced::Failed(e) =
+                    trace::<T>(path, Some(&serde_yaml::Value::Mapping(sample.clone())))
+                {
+                    let field = match e
+                        .strip_prefix("missing field `")
+                        .and_then(|f| f.split('`').next())
+                    {
+                        Some(field) if !required.contains(&field.to_string()) => field.to_string(),
+                        _ => break,
+                    };
+                    let value = properties
+                        .get(&field)
+                        .map_or(serde_yaml::Value::Null, |s| schema_sample(s, &defs.samples));
+                    sample.insert(field.as_str().into(), value);
+                    required.push(field);
+                }
+                defs.samples
+                    .insert(name, serde_yaml::Value::Mapping(sample));
+                let mut schema = serde_json::json!({
+                    "type": "object",
+                    "properties": properties,
+                    "additionalProperties": false,
+                });
+                if !required.is_empty() {
+                    schema["required"] = required.into();
+                }
+                defs.schemas.insert(name.to_string(), schema);
+            }
+            serde_json::json!({"$ref": format!("#/$defs/{}", name)})
+        }
+        Shape::Any => {
+            // what untagged enums take shows only by trying values on them
+            if let Traced::Accepted = trace::<T>(path, Some(&serde_yaml::Value::Null)) {
+                return serde_json::json!({});
+            }
+            let mut schemas = probe_schemas::<T>(path, &defs.probes, |p| p);
+            let items =
+                probe_schemas::<T>(path, &defs.probes, |p| serde_yaml::Value::Sequence(vec![p]));
+            if !items.is_empty() {
+                schemas.push(serde_json::json!({"type": "array", "items": any_of(items)}));
+            }
+            any_of(schemas)
+        }
+    }
+}
+
+/// Adds `properties` to the struct schema `name` in `defs`.
+fn extend_schema(defs: &mut SchemaDefs, name: &str, properties: serde_json::Value) {
+    if let (Some(def), serde_json::Value::Object(properties)) = (
+        defs.schemas
+            .get_mut(name)
+            .and_then(|d| d.get_mut("properties"))
+            .and_then(|p| p.as_object_mut()),
+        properties,
+    ) {
+        def.extend(properties);
+    }
+}
+
+/// JSON Schema for definitions files, as they are written before includes,
+/// profiles and matrices are expanded.
+pub fn definitions_schema() -> serde_json::Value {
+    let mut defs = SchemaDefs::default();
+    let command = path_schema::<StructuredCommand>(&mut vec![], &mut defs);
+    defs.probes = vec![
+        (true.into(), serde_json::json!({"type": "boolean"})),
+        ((-1).into(), serde_json::json!({"type": "integer"})),
+        (
+            1.into(),
+            serde_json::json!({"type": "integer", "minimum": 0}),
+        ),
+        ("x".into(), serde_json::json!({"type": "string"})),
+        (defs.samples["StructuredCommand"].clone(), command),
+    ];
+    let root = path_schema::<DefinitionFile>(&mut vec![], &mut defs);
+
+    extend_schema(
+        &mut defs,
+        "DefinitionFile",
+        serde_json::json!({"include": {"type": "array", "items": {"type": "string"}}}),
+    );
+    if let Some(d) = defs.schemas.get_mut("DefinitionFile") {
+        d["patternProperties"] = serde_json::json!({"^x-": {}});
+    }
+    extend_schema(
+        &mut defs,
+        "TemplatesAndInstances",
+        serde_json::json!({"profiles": {"type": "object", "additionalProperties": {"type": "object"}}}),
+    );
+    extend_schema(
+        &mut defs,
+        "InstanceServiceDef",
+        serde_json::json!({
+            "Matrix": {
+                "type": "object",
+                "additionalProperties": {"anyOf": [
+                    {"type": "array", "items": {"type": ["string", "integer", "number", "boolean"]}},
+                    {
+                        "type": "string",
+                        "pattern": r"^\s*-?\d+\s*\.\.=?\s*-?\d+\s*$",
+                        "examples": ["1..=3"],
+                    },
+                ]},
+            },
+            "Service": {"$ref": "#/$defs/InstanceService"},
+        }),
+    );
+    // serde leaves aliases out of a struct's field names
+    if let Some(d) = defs.schemas.get_mut("StructuredCommand") {
+        for (alias, field) in [("cmd", "Cmd"), ("args", "Args")] {
+            d["properties"][alias] = d["properties"][field].clone();
+        }
+        d["anyOf"] = serde_json::json!([{"required": ["Cmd"]}, {"required": ["cmd"]}]);
+        if let Some(d) = d.as_object_mut() {
+            d.remove("required");
+        }
+    }
+    // an instance can clear a template's Service field by setting it to null
+    if let Some(mut service) = defs.schemas.get("Service").cloned() {
+        if let Some(properties) = service["properties"].as_object_mut() {
+            for p in properties.values_mut() {
+                *p = serde_json::json!({"anyOf": [p.take(), {"type": "null"}]});
+            }
+        }
+        defs.schemas.insert("InstanceService".to_string(), service);
+    }
+
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "gen-systemd-svcs definitions file",
+        "$ref": root["$ref"],
+        "$defs": defs.schemas,
+    })
+}
+
+fn create_dir(dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dir).map_err(|e| Error::Write(format!("{}: {}", dir.display(), e)))
+}
+
+fn write_output(dst: &Path, contents: String) -> Result<(), Error> {
+    fs::write(dst, contents).map_err(|e| Error::Write(format!("{}: {}", dst.display(), e)))
+}
+
+fn read_existing(dst: &Path) -> Result<Option<String>, Error> {
+    match fs::read_to_string(dst) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io(format!("{}: {}", dst.display(), e))),
+    }
+}
+
+const DIFF_CONTEXT: usize = 3;
+
+fn hunk_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+/// Renders a unified diff from `old` to `new`, or an empty string when they
+/// are equal. Lines are matched by their longest common subsequence.
+fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let a: Vec<&str> = old.split_inclusive('\n').collect();
+    let b: Vec<&str> = new.split_inclusive('\n').collect();
+
+    // lcs[i][j] is the length of the common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = match a[i] == b[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    let mut ops = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            ops.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(('-', a[i]));
+            i += 1;
+        } else {
+            ops.push(('+', b[j]));
+            j += 1;
+        }
+    }
+
+    let changes: Vec<usize> = (0..ops.len()).filter(|&k| ops[k].0 != ' ').collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    let mut memo = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let mut c = 0;
+    while c < changes.len() {
+        let start = changes[c].saturating_sub(DIFF_CONTEXT);
+        let mut end = (changes[c] + DIFF_CONTEXT + 1).min(ops.len());
+        while c + 1 < changes.len() && changes[c + 1] <= end + DIFF_CONTEXT {
+            c += 1;
+            end = (changes[c] + DIFF_CONTEXT + 1).min(ops.len());
+        }
+        c += 1;
+
+        let old_start = ops[..start].iter().filter(|(op, _)| *op != '+').count();
+        let new_start = ops[..start].iter().filter(|(op, _)| *op != '-').count();
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|(op, _)| *op != '+').count();
+        let new_len = hunk.iter().filter(|(op, _)| *op != '-').count();
+        memo += &format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old_start, old_len),
+            hunk_range(new_start, new_len)
+        );
+        for (op, line) in hunk {
+            memo.push(*op);
+            memo += line;
+            if !line.ends_with('\n') {
+                memo += "\n\\ No newline at end of file\n";
+            }
+        }
+    }
+    memo
+}
+
+/// Settings for `load` and `generate`, mirroring the command line flags.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Format of the definitions file, detected from its extension when None
+    pub format: Option<Format>,
+    /// Apply each def's overrides for this profile
+    pub profile: Option<String>,
+    /// Interpolation variables, overriding Vars in the definitions
+    pub vars: BTreeMap<String, String>,
+    /// Write each service as a name.service.d/override.conf drop-in
+    pub drop_in: bool,
+    /// Generate user units
+    pub user: bool,
+    /// Also write a 90-generated.preset file covering every generated unit
+    pub preset: bool,
+    /// Also write sysusers.d fragments for the User= and Group= of each service
+    pub sysusers: bool,
+    /// Treat warnings as errors
+    pub strict: bool,
+    /// Only list the files `generate` would write
+    pub dry_run: bool,
+    /// Diff each file against the one on disk instead of writing it
+    pub diff: bool,
+    /// List the files on disk that differ from the generated ones instead of
+    /// writing them
+    pub check: bool,
+}
+
+/// Reads interpolation variables from a YAML mapping.
+pub fn read_var_file(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    let file = File::open(path).map_err(|e| Error::Io(format!("{}: {}", path.display(), e)))?;
+    let value: serde_yaml::Value = serde_yaml::from_reader(BufReader::new(file))
+        .map_err(|e| Error::Parse(format!("{}: {}", path.display(), e)))?;
+    if !value.is_mapping() {
+        return Err(Error::Parse(format!(
+            "{}: expected a mapping of variables",
+            path.display()
+        )));
+    }
+    Ok(string_map(Some(&value)))
+}
+
+/// Reads the definitions file at `path` and everything it includes, then
+/// applies environment variables, the selected profile, matrices and
+/// interpolation variables.
+pub fn load(path: &Path, options: &Options) -> Result<DefinitionFile, Error> {
+    let mut seen = vec![];
+    visit_definitions(path, &mut seen)?;
+    load_source(&read_definitions(path)?, path, seen, options)
+}
+
+/// Like `load`, but takes the definitions from `src` instead of reading
+/// `path`, which still names the input in errors and anchors its includes.
+pub fn load_str(src: &str, path: &Path, options: &Options) -> Result<DefinitionFile, Error> {
+    load_source(src, path, vec![], options)
+}
+
+fn load_source(
+    src: &str,
+    path: &Path,
+    mut seen: Vec<PathBuf>,
+    options: &Options,
+) -> Result<DefinitionFile, Error> {
+    let format = options.format.unwrap_or_else(|| Format::detect(path));
+    let mut sources = vec![];
+    let mut value = load_definitions(src, path, format, &mut seen, &mut sources)?;
+    expand_env(&mut value);
+    let instance_origins = apply_profiles(&mut value, options.profile.as_deref())
+        .and_then(|_| expand_matrices(&mut value))
+        .map_err(Error::Validation)?;
+    interpolate_instances(&mut value, &options.vars).map_err(Error::Validation)?;
+    DefinitionFile::deserialize(&value).map_err(|e| {
+        let root = (path, format, src);
+        Error::Parse(definitions_error(
+            &value,
+            &e.to_string(),
+            &sources,
+            &instance_origins,
+            root,
+        ))
+    })
+}
+
+/// Renders every unit described by `def_file` as pairs of a file name,
+/// relative to the output directory, and its contents, which can be changed
+/// before they are turned into text with `to_string`. Nothing is written, and
+/// warnings are added to `warnings` prefixed with the unit they are about.
+pub fn render(
+    def_file: &DefinitionFile,
+    options: &Options,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<(String, GeneratedFile)>, Error> {
+    let mut units: Vec<(String, GeneratedFile)> = vec![];
+    let mut presets: Vec<String> = vec![];
+
+    for slice in &def_file.slices {
+        units.push((
+            format!("{}.slice", slice.name),
+            GeneratedFile::Unit(
+                resolve_slice(slice)
+                    .map_err(|e| Error::Validation(format!("{}.slice: {}", slice.name, e)))?,
+            ),
+        ));
+    }
+
+    for network in &def_file.networks {
+        for instance in network.instances.clone() {
+            let name = instance.name.clone();
+            let netdev = resolve_netdev(
+                instance.net_dev.clone(),
+                network.template.net_dev.clone(),
+                &name,
+            );
+            let resolved = resolve_network(instance, network.template.clone());
+            units.push((format!("{}.network", name), GeneratedFile::Unit(resolved)));
+
+            if let Some(resolved) = netdev {
+                units.push((format!("{}.netdev", name), GeneratedFile::Unit(resolved)));
+            }
+        }
+    }
+
+    for def in &def_file.defs {
+        let mut def = def.clone();
+        def.template = resolve_template(def.template, &def_file.templates, &mut vec![])
+            .map_err(Error::Validation)?;
+        let unit_names: Vec<String> = def
+            .instances
+            .iter()
+            .map(|i| match &def.native_template {
+                Some(native) => format!("{}@{}", native, i.unit.name),
+                None => i.unit.name.clone(),
+            })
+            .collect();
+
+        if let Some(target) = &def.target {
+            units.push((
+                format!("{}.target", target.name),
+                GeneratedFile::Unit(resolve_target(target, &unit_names)),
+            ));
+        }
+
+        if let Some(native) = &def.native_template {
+            for instance in &def.instances {
+                let fields = ignored_native_fields(instance);
+                if !fields.is_empty() {
+                    return Err(Error::Validation(format!(
+                        "{}@{}: {} cannot be set on a native template instance, set them in the template",
+                        native,
+                        instance.unit.name,
+                        fields.join(", ")
+                    )));
+                }
+            }
+            let enabled: Vec<String> = def
+                .instances
+                .iter()
+                .filter(|i| i.enabled)
+                .map(|i| i.unit.name.clone())
+                .collect();
+            if !enabled.is_empty() {
+                presets.push(format!("enable {}@.service {}", native, enabled.join(" ")));
+            }
+        }
+
+        // tmpfiles.d does not expand %i, so a native template's directories
+        // are written out for each listed instance instead
+        let mut native_tmpfiles = vec![];
+        if def.native_template.is_some() {
+            for i in &def.instances {
+                let vars = instance_vars(&options.vars, &def.template, i, false);
+                let template = interpolate_template(&def.template, &vars)
+                    .map_err(|e| Error::Validation(format!("{}: {}", i.unit.name, e)))?;
+                native_tmpfiles
+                    .extend(resolve_tmpfiles(None, template.directories).unwrap_or_default());
+            }
+        }
+        let native_tmpfiles = Some(dedupe_list(true, native_tmpfiles)).filter(|l| !l.is_empty());
+
+        let instances = match &def.native_template {
+            Some(native) => {
+                let instance = native_template_instance(native, &def.template);
+                let vars = instance_vars(&options.vars, &def.template, &instance, true);
+                let template = interpolate_template(&def.template, &vars)
+                    .map_err(|e| Error::Validation(format!("{}@: {}", native, e)))?;
+                vec![native_template_instance(native, &template)]
+            }
+            None => def.instances,
+        };
+
+        for mut instance in instances {
+            let name = instance.unit.name.clone();
+            let description = instance.unit.description.clone().unwrap_or(name.clone());
+            let vars = instance_vars(
+                &options.vars,
+                &def.template,
+                &instance,
+                def.native_template.is_some(),
+            );
+            let template = interpolate_template(&def.template, &vars)
+                .map_err(|e| Error::Validation(format!("{}: {}", name, e)))?;
+            let target_unit = def.target.as_ref().map(|t| format!("{}.target", t.name));
+            if let Some(target_unit) = &target_unit {
+                instance
+                    .unit
+                    .part_of
+                    .get_or_insert_with(Vec::new)
+                    .push(target_unit.clone());
+            }
+            let timer = resolve_timer(instance.timer.clone(), template.timer.clone(), &description);
+            let accept = merge_socket(instance.socket.clone(), template.socket.clone())
+                .and_then(|s| s.accept)
+                .unwrap_or(false);
+            let socket = resolve_socket(
+                instance.socket.clone(),
+                template.socket.clone(),
+                // an Accept socket is not a template, so it has no %I
+                match (accept, &def.native_template) {
+                    (true, Some(native)) => native,
+                    _ => &description,
+                },
+            );
+            let socket_unit = match (&socket, accept, &def.native_template) {
+                (None, _, _) => None,
+                (Some(_), true, None) => {
+                    return Err(Error::Validation(format!(
+                        "{}: a socket with Accept: true starts a {}@.service for each connection, so it needs a native_template",
+                        name, name
+                    )))
+                }
+                // systemd ties each connection's instance to the socket itself
+                (Some(_), true, Some(_)) => None,
+                (Some(_), false, Some(_)) => Some(format!("{}%i.socket", name)),
+                (Some(_), false, None) => Some(format!("{}.socket", name)),
+            };
+            let nspawn = resolve_nspawn(instance.nspawn.clone(), template.nspawn.clone());
+            let tmpfiles = match def.native_template {
+                Some(_) => native_tmpfiles.clone(),
+                None => {
+                    resolve_tmpfiles(instance.directories.clone(), template.directories.clone())
+                }
+            };
+            let sysusers = match options.sysusers || def.sysusers {
+                true => {
+                    let template_service = match instance.inherit_service {
+                        true => template.service.clone(),
+                        false => Service::default(),
+                    };
+                    // sysusers.d does not expand specifiers such as a template's %I
+                    let gecos = strip_specifiers(&description);
+                    resolve_sysusers(
+                        &resolve_service(instance.service.clone(), template_service)
+                            .map_err(|e| Error::Validation(format!("{}: {}", name, e)))?,
+                        match gecos.is_empty() {
+                            true => name.trim_end_matches('@'),
+                            false => &gecos,
+                        },
+                    )
+                }
+                false => None,
+            };
+            if def.native_template.is_none() {
+                let verb = match instance.enabled {
+                    true => "enable",
+                    false => "disable",
+                };
+                presets.push(format!("{} {}.service", verb, name));
+                if timer.is_some() {
+                    presets.push(format!("{} {}.timer", verb, name));
+                }
+                if socket.is_some() {
+                    presets.push(format!("{} {}.socket", verb, name));
+                }
+            }
+            let resolve_options = ResolveOptions {
+                drop_in: options.drop_in || instance.drop_in,
+                user_scope: options.user || matches!(def.scope, Some(Scope::User)),
+            };
+            let mut instance_warnings = vec![];
+            let resolved = resolve(
+                instance,
+                template,
+                target_unit.as_deref(),
+                socket_unit.as_deref(),
+                resolve_options,
+                &mut instance_warnings,
+            )
+            .map_err(|e| Error::Validation(format!("{}: {}", name, e)))?;
+            warnings.extend(instance_warnings.iter().map(|w| format!("{}: {}", name, w)));
+            if options.strict && !instance_warnings.is_empty() {
+                return Err(Error::Validation(format!(
+                    "{}: warnings are errors with --strict",
+                    name
+                )));
+            }
+            let file = match resolve_options.drop_in {
+                true => format!("{}.service.d/override.conf", name),
+                false => format!("{}.service", name),
+            };
+            units.push((file, GeneratedFile::Unit(resolved)));
+
+            if let Some(resolved) = timer {
+                units.push((format!("{}.timer", name), GeneratedFile::Unit(resolved)));
+            }
+
+            if let Some(resolved) = socket {
+                // an Accept socket is foo.socket for foo@.service
+                let socket_name = match accept {
+                    true => name.trim_end_matches('@'),
+                    false => &name,
+                };
+                units.push((
+                    format!("{}.socket", socket_name),
+                    GeneratedFile::Unit(resolved),
+                ));
+            }
+
+            if let Some(resolved) = nspawn {
+                units.push((format!("{}.nspawn", name), GeneratedFile::Unit(resolved)));
+            }
+
+            if let Some(resolved) = tmpfiles {
+                units.push((
+                    format!("tmpfiles.d/{}.conf", name.trim_end_matches('@')),
+                    GeneratedFile::Lines(resolved),
+                ));
+            }
+
+            if let Some(resolved) = sysusers {
+                units.push((
+                    format!("sysusers.d/{}.conf", name.trim_end_matches('@')),
+                    GeneratedFile::Lines(resolved),
+                ));
+            }
+        }
+    }
+
+    if options.preset {
+        units.push((
+            "90-generated.preset".to_string(),
+            GeneratedFile::Lines(presets),
+        ));
+    }
+    for (file, contents) in &units {
+        check_newlines(file, contents)?;
+    }
+    Ok(units)
+}
+
+/// What `generate` did, for the caller to report.
+#[derive(Clone, Debug, Default)]
+pub struct Generated {
+    /// Every file written, or that would have been without `dry_run`, `diff`
+    /// or `check`
+    pub files: Vec<PathBuf>,
+    /// A unified diff for each file that differs from the one on disk
+    pub diffs: Vec<String>,
+    /// The files, relative to the output directory, that differ from the
+    /// ones on disk
+    pub outdated: Vec<String>,
+}
+
+/// Writes every unit described by `def_file` into `out_dst`. Nothing is
+/// written unless every unit renders, or at all with `dry_run`, `diff` or
+/// `check`. Warnings are added to `warnings` even when rendering fails.
+pub fn generate(
+    def_file: &DefinitionFile,
+    out_dst: &Path,
+    options: &Options,
+    warnings: &mut Vec<String>,
+) -> Result<Generated, Error> {
+    let units = render(def_file, options, warnings)?;
+
+    let write = !options.dry_run && !options.diff && !options.check;
+    if options.user && write {
+        create_dir(out_dst)?;
+    }
+    let mut generated = Generated::default();
+    for (file, contents) in units {
+        let contents = contents.to_string();
+        let dst = out_dst.join(&file);
+        let existing = match options.diff || options.check {
+            true => read_existing(&dst)?,
+            false => None,
+        };
+        if options.diff {
+            let name = dst.display().to_string();
+            let diff = match &existing {
+                Some(existing) => unified_diff(existing, &contents, &name, &name),
+                None => unified_diff("", &contents, "/dev/null", &name),
+            };
+            if !diff.is_empty() {
+                generated.diffs.push(diff);
+            }
+        }
+        if options.check && existing.as_ref() != Some(&contents) {
+            generated.outdated.push(file.clone());
+        }
+        if write {
+            if let (true, Some(dir)) = (file.contains('/'), dst.parent()) {
+                create_dir(dir)?;
+            }
+            write_output(&dst, contents)?;
+        }
+        generated.files.push(dst);
+    }
+    Ok(generated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(src: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(src).unwrap()
+    }
+
+    #[test]
+    fn toml_locate() {
+        let src = "[[defs]]\n[defs.template.Service]\nNice = 'high'\n\n[[defs.instances]]\nUnit = { Name = 'a', After = ['x', 3] }\n";
+        let path = |p: &str| -> Vec<serde_yaml::Value> { serde_yaml::from_str(p).unwrap() };
+        let locate = |p: &str| locate(src, Format::Toml, &path(p));
+        assert_eq!(locate("[defs, 0, template, Service, Nice]"), Some((3, 8)));
+        assert_eq!(locate("[defs, 0, template, Service]"), Some((2, 1)));
+        assert_eq!(
+            locate("[defs, 0, instances, 0, Unit, After, 1]"),
+            Some((6, 36))
+        );
+        // falls back to the closest ancestor in the file
+        assert_eq!(locate("[defs, 0, instances, 0, Service]"), Some((5, 1)));
+        assert_eq!(locate("[defs, 0, template, Unit]"), Some((1, 1)));
+    }
+
+    fn render_str(src: &str, options: &Options) -> BTreeMap<String, String> {
+        let def_file = load_str(src, Path::new("test.yaml"), options).unwrap();
+        render(&def_file, options, &mut vec![])
+            .unwrap()
+            .into_iter()
+            .map(|(file, contents)| (file, contents.to_string()))
+            .collect()
+    }
+
+    /// The values of every `key=` line in `unit`.
+    fn values<'a>(unit: &'a str, key: &str) -> Vec<&'a str> {
+        unit.lines()
+            .filter_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+            .collect()
+    }
+
+    #[test]
+    fn render_merge_strategies() {
+        let units = render_str(
+            "
+defs:
+  - template:
+      Unit: {Requires: [t.service]}
+      Service: {ExecStart: /bin/x}
+    instances:
+      - Unit: {Name: append, Description: A, Requires: [i.service]}
+      - Unit: {Name: prepend, Description: P, Requires: [i.service], Merge: {Requires: prepend}}
+      - Unit: {Name: replace, Description: R, Requires: [i.service], Merge: {Requires: replace}}
+      - Unit: {Name: kept, Description: K, Merge: {Requires: replace}}
+",
+            &Options::default(),
+        );
+        let requires = |name: &str| values(&units[&format!("{}.service", name)], "Requires");
+        assert_eq!(requires("append"), ["t.service", "i.service"]);
+        assert_eq!(requires("prepend"), ["i.service", "t.service"]);
+        assert_eq!(requires("replace"), ["i.service"]);
+        assert_eq!(requires("kept"), ["t.service"]);
+    }
+
+    #[test]
+    fn render_dedupe() {
+        let units = render_str(
+            "
+defs:
+  - template:
+      Unit: {After: [x.service]}
+      Service: {ExecStart: /bin/x}
+    instances:
+      - Unit: {Name: a, Description: A, After: [x.service, y.service]}
+      - Unit: {Name: b, Description: B, After: [x.service, y.service], Dedupe: false}
+",
+            &Options::default(),
+        );
+        assert_eq!(
+            values(&units["a.service"], "After"),
+            ["x.service", "y.service"]
+        );
+        assert_eq!(
+            values(&units["b.service"], "After"),
+            ["x.service", "x.service", "y.service"]
+        );
+    }
+
+    #[test]
+    fn render_null_clears_template_service_fields() {
+        let src = "
+defs:
+  - template:
+      Unit: {}
+      Service: {ExecStart: /bin/x, Nice: 5, User: app}
+    instances:
+      - Unit: {Name: a, Description: A}
+        Service: {Nice: null}
+";
+        let units = render_str(src, &Options::default());
+        assert!(values(&units["a.service"], "Nice").is_empty());
+        assert_eq!(values(&units["a.service"], "User"), ["app"]);
+
+        let typo = src.replace("Nice: null", "ExecStrat: null");
+        let e = load_str(&typo, Path::new("test.yaml"), &Options::default()).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("unknown field `ExecStrat`, did you mean `ExecStart`?"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn render_matrix_expansion() {
+        let src = "
+defs:
+  - template:
+      Unit: {}
+      Service: {ExecStart: '/bin/srv --port {{port}} --zone {{zone}}'}
+    instances:
+      - Matrix: {port: [80, 81], zone: [a, b]}
+        Unit: {Name: 'web-{{port}}-{{zone}}', Description: 'Web {{port}}'}
+";
+        let units = render_str(src, &Options::default());
+        let names: Vec<&str> = units.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            [
+                "web-80-a.service",
+                "web-80-b.service",
+                "web-81-a.service",
+                "web-81-b.service"
+            ]
+        );
+        assert_eq!(
+            values(&units["web-81-b.service"], "ExecStart"),
+            ["/bin/srv --port 81 --zone b"]
+        );
+        assert_eq!(
+            values(&units["web-81-b.service"], "Description"),
+            ["Web 81"]
+        );
+
+        let duplicate = src.replace("-{{zone}}'", "'");
+        let e = load_str(&duplicate, Path::new("test.yaml"), &Options::default()).unwrap_err();
+        assert!(
+            e.to_string().contains("duplicate instance name web-80"),
+            "{}",
+            e
+        );
+
+        for range in ["5..1", "1..1"] {
+            let empty = src.replace("[80, 81]", range);
+            let e = load_str(&empty, Path::new("test.yaml"), &Options::default()).unwrap_err();
+            assert!(matches!(e, Error::Validation(_)), "{}", e);
+            assert!(
+                e.to_string()
+                    .contains(&format!("Matrix port={} is an empty range", range)),
+                "{}",
+                e
+            );
+        }
+        let single = render_str(&src.replace("[80, 81]", "1..=1"), &Options::default());
+        assert!(single.contains_key("web-1-a.service"));
+    }
+
+    #[test]
+    fn render_drop_in() {
+        let src = "
+defs:
+  - template:
+      Unit: {}
+      Service: {ExecStart: /bin/x, ExecStartPre: [/bin/a, /bin/b]}
+    instances:
+      - Unit: {Name: a}
+";
+        let options = Options {
+            drop_in: true,
+            ..Default::default()
+        };
+        let units = render_str(src, &options);
+        let unit = &units["a.service.d/override.conf"];
+        // a drop-in has to reset the lists it sets, or systemd appends to them
+        assert_eq!(values(unit, "ExecStart"), ["", "/bin/x"]);
+        assert_eq!(values(unit, "ExecStartPre"), ["", "/bin/a", "/bin/b"]);
+        assert!(values(unit, "Description").is_empty());
+        assert!(!unit.contains("[Install]"));
+
+        let def_file = load_str(src, Path::new("test.yaml"), &Options::default()).unwrap();
+        let e = render(&def_file, &Options::default(), &mut vec![]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "a: Description is required unless the unit is a drop-in"
+        );
+    }
+
+    #[test]
+    fn unified_diff_hunks() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n", "old", "new"), "");
+
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = "1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n";
+        assert_eq!(
+            unified_diff(old, new, "old", "new"),
+            "--- old\n+++ new\n@@ -1,6 +1,6 @@\n 1\n 2\n-3\n+three\n 4\n 5\n 6\n@@ -10,3 +10,4 @@\n 10\n 11\n 12\n+13\n"
+        );
+
+        // changes within twice the context of each other share a hunk
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n";
+        let new = "1\nx\n3\n4\n5\n6\ny\n8\n";
+        assert_eq!(
+            unified_diff(old, new, "old", "new"),
+            "--- old\n+++ new\n@@ -1,8 +1,8 @@\n 1\n-2\n+x\n 3\n 4\n 5\n 6\n-7\n+y\n 8\n"
+        );
+    }
+
+    #[test]
+    fn unified_diff_edges() {
+        assert_eq!(
+            unified_diff("", "a\n", "old", "new"),
+            "--- old\n+++ new\n@@ -0,0 +1 @@\n+a\n"
+        );
+        assert_eq!(
+            unified_diff("a\n", "", "old", "new"),
+            "--- old\n+++ new\n@@ -1 +0,0 @@\n-a\n"
+        );
+        assert_eq!(
+            unified_diff("a\nb", "a\nb\n", "old", "new"),
+            "--- old\n+++ new\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+b\n"
+        );
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        // a swap of neighbours counts once
+        assert_eq!(edit_distance("ExecStrat", "ExecStart"), 1);
+        assert_eq!(edit_distance("ab", "ba"), 1);
+    }
+
+    #[test]
+    fn suggest_fields() {
+        let msg = "defs[0]: unknown field `ExecStrat`, expected one of `ExecStart`, `ExecStop`";
+        assert_eq!(
+            suggest_field(msg),
+            "defs[0]: unknown field `ExecStrat`, did you mean `ExecStart`?"
+        );
+        // case is ignored when comparing
+        assert_eq!(
+            suggest_field("unknown field `user`, expected `User` or `Group`"),
+            "unknown field `user`, did you mean `User`?"
+        );
+        // nothing close enough is left alone
+        let msg = "unknown field `Bogus`, expected one of `ExecStart`, `ExecStop`";
+        assert_eq!(suggest_field(msg), msg);
+        assert_eq!(suggest_field("invalid type"), "invalid type");
+    }
+
+    #[test]
+    fn render_network_without_match() {
+        let units = render_str(
+            "
+networks:
+  - template:
+      Network: {DHCP: 'yes'}
+    instances:
+      - Name: br0
+      - Name: eth1
+        Match: {MACAddress: '00:11:22:33:44:55'}
+defs: []
+",
+            &Options::default(),
+        );
+        assert_eq!(values(&units["br0.network"], "Name"), ["br0"]);
+        assert!(values(&units["eth1.network"], "Name").is_empty());
+    }
+
+    #[test]
+    fn render_inherited_install_dedupe() {
+        let units = render_str(
+            "
+defs:
+  - template:
+      Unit: {}
+      Service: {ExecStart: /bin/x}
+      Install: {WantedBy: a.target, RequiredBy: [r.target], Also: [x.socket]}
+    instances:
+      - Unit: {Name: a, Description: A}
+        InheritInstall: true
+        Install: {WantedBy: [a.target, b.target], RequiredBy: [r.target], Also: [x.socket]}
+      - Unit: {Name: b, Description: B, Dedupe: false}
+        InheritInstall: true
+        Install: {WantedBy: [a.target]}
+",
+            &Options::default(),
+        );
+        let a = &units["a.service"];
+        assert_eq!(values(a, "WantedBy"), ["a.target", "b.target"]);
+        assert_eq!(values(a, "RequiredBy"), ["r.target"]);
+        assert_eq!(values(a, "Also"), ["x.socket"]);
+        assert_eq!(
+            values(&units["b.service"], "WantedBy"),
+            ["a.target", "a.target"]
+        );
+    }
+
+    #[test]
+    fn render_environment_escapes_specifiers() {
+        let src = "
+defs:
+  - template:
+      Unit: {}
+      Service: {ExecStart: /bin/x, Environment: {A: '50%', B: 'say \"hi\"'}}
+    instances:
+      - Unit: {Name: a, Description: A}
+";
+        let units = render_str(src, &Options::default());
+        assert_eq!(
+            values(&units["a.service"], "Environment"),
+            [r#""A=50%%""#, r#""B=say \"hi\"""#]
+        );
+
+        for key in ["'A B'", "'A=B'", "''"] {
+            let src = src.replace("A: '50%'", &format!("{}: x", key));
+            let def_file = load_str(&src, Path::new("test.yaml"), &Options::default()).unwrap();
+            let e = render(&def_file, &Options::default(), &mut vec![]).unwrap_err();
+            assert!(
+                e.to_string().contains("must not be empty or contain"),
+                "{}",
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn render_native_sysusers() {
+        let src = "
+defs:
+  - native_template: worker
+    sysusers: true
+    template:
+      Unit: {}
+      Service: {ExecStart: /bin/x, User: worker}
+    instances:
+      - Unit: {Name: a}
+";
+        let units = render_str(src, &Options::default());
+        assert!(units["sysusers.d/worker.conf"].contains("u worker - \"worker\"\n"));
+
+        let described = src.replace("Unit: {}", "Unit: {Description: 'Worker %I at 100%%'}");
+        let units = render_str(&described, &Options::default());
+        assert!(units["sysusers.d/worker.conf"].contains("u worker - \"Worker at 100%\"\n"));
+    }
+
+    #[test]
+    fn render_native_tmpfiles() {
+        let units = render_str(
+            "
+defs:
+  - native_template: worker
+    template:
+      Unit: {}
+      Service: {ExecStart: '/bin/x {{name}}'}
+      Directories:
+        - {Path: '/run/worker-{{name}}', Mode: '0750'}
+        - {Path: /var/lib/worker}
+    instances:
+      - Unit: {Name: a}
+      - Unit: {Name: b}
+",
+            &Options::default(),
+        );
+        assert_eq!(
+            values(&units["worker@.service"], "ExecStart"),
+            ["/bin/x %i"]
+        );
+        assert_eq!(
+            units["tmpfiles.d/worker.conf"]
+                .lines()
+                .filter(|l| l.starts_with("d "))
+                .collect::<Vec<_>>(),
+            [
+                "d /run/worker-a 0750 - - -",
+                "d /var/lib/worker - - - -",
+                "d /run/worker-b 0750 - - -",
+            ]
+        );
+    }
+
+    #[test]
+    fn render_nspawn_skips_empty_sections() {
+        let units = render_str(
+            "
+defs:
+  - template:
+      Unit: {}
+      Service: {ExecStart: /bin/x}
+      Nspawn: {Boot: true}
+    instances:
+      - Unit: {Name: a, Description: A}
+      - Unit: {Name: b, Description: B}
+        Nspawn: {Boot: false, Bind: [/srv]}
+",
+            &Options::default(),
+        );
+        let a = &units["a.nspawn"];
+        assert!(a.contains("[Exec]\nBoot=true\n"));
+        assert!(!a.contains("[Files]") && !a.contains("[Network]"));
+        let b = &units["b.nspawn"];
+        assert!(b.contains("[Exec]\nBoot=false\n") && b.contains("[Files]\nBind=/srv\n"));
+        assert!(!b.contains("[Network]"));
+    }
+
+    /// A value for `schema` that sets every property it names, taking the
+    /// `variant`th alternative wherever there is a choice.
+    fn schema_document(
+        schema: &serde_json::Value,
+        defs: &serde_json::Value,
+        variant: usize,
+        depth: usize,
+    ) -> serde_yaml::Value {
+        assert!(depth < 16, "schema nests too deep at {}", schema);
+        let pick = |list: &Vec<serde_json::Value>| list[variant % list.len()].clone();
+        if let Some(name) = schema["$ref"].as_str() {
+            let name = name.trim_start_matches("#/$defs/");
+            return schema_document(&defs[name], defs, variant, depth + 1);
+        }
+        if let Some(any_of) = schema["anyOf"].as_array() {
+            return schema_document(&pick(any_of), defs, variant, depth + 1);
+        }
+        if let Some(values) = schema["enum"].as_array().or(schema["examples"].as_array()) {
+            return serde_yaml::to_value(pick(values)).unwrap();
+        }
+        let kind = match &schema["type"] {
+            serde_json::Value::Array(kinds) => pick(kinds),
+            kind => kind.clone(),
+        };
+        match kind.as_str() {
+            Some("boolean") => variant.is_multiple_of(2).into(),
+            Some("integer") => 1.into(),
+            Some("number") => 1.5.into(),
+            Some("string") => "x".into(),
+            Some("null") => serde_yaml::Value::Null,
+            Some("array") => match schema.get("items") {
+                Some(items) => serde_yaml::Value::Sequence(vec![schema_document(
+                    items,
+                    defs,
+                    variant,
+                    depth + 1,
+                )]),
+                None => serde_yaml::Value::Sequence(vec!["x".into()]),
+            },
+            Some("object") => {
+                let mut m = serde_yaml::Mapping::new();
+                for (k, v) in schema["properties"].as_object().into_iter().flatten() {
+                    m.insert(
+                        k.as_str().into(),
+                        schema_document(v, defs, variant, depth + 1),
+                    );
+                }
+                if let Some(v) = schema.get("additionalProperties").filter(|v| v.is_object()) {
+                    m.insert("key".into(), schema_document(v, defs, variant, depth + 1));
+                }
+                serde_yaml::Value::Mapping(m)
+            }
+            _ => "x".into(),
+        }
+    }
+
+    #[test]
+    fn schema_fields_deserialize() {
+        let schema = definitions_schema();
+        let defs = &schema["$defs"];
+        let root = serde_json::json!({"$ref": schema["$ref"]});
+        for variant in 0..4 {
+            let mut document = schema_document(&root, defs, variant, 0);
+            // includes name files to read
+            document.as_mapping_mut().unwrap().remove("include");
+            // and every instance a matrix makes needs its own name
+            for instance in document["defs"][0]["instances"].as_sequence_mut().unwrap() {
+                instance["Unit"]["Name"] = "x-{{key}}".into();
+            }
+            let src = serde_yaml::to_string(&document).unwrap();
+            if let Err(e) = load_str(&src, Path::new("schema.yaml"), &Options::default()) {
+                panic!("variant {}: {}\n{}", variant, e, src);
+            }
+        }
+    }
+
+    #[test]
+    fn render_socket_ports() {
+        let units = render_str(
+            "
+defs:
+  - template:
+      Unit: {}
+      Service: {ExecStart: /bin/x}
+      Socket: {ListenStream: 8080, ListenDatagram: '127.0.0.1:53'}
+    instances:
+      - Unit: {Name: a, Description: A}
+",
+            &Options::default(),
+        );
+        assert_eq!(values(&units["a.socket"], "ListenStream"), ["8080"]);
+        assert_eq!(
+            values(&units["a.socket"], "ListenDatagram"),
+            ["127.0.0.1:53"]
+        );
+    }
+
+    #[test]
+    fn migrate_versions_unversioned_files_once() {
+        let dir = std::env::temp_dir().join(format!("gen-systemd-svcs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("defs.yaml");
+        fs::write(&path, "defs: []\n").unwrap();
+        assert_eq!(migrate(&path, None).unwrap(), Some(1));
+        let upgraded = fs::read_to_string(&path).unwrap();
+        assert_eq!(yaml(&upgraded), yaml("{version: 1, defs: []}"));
+
+        // a current file keeps its comments because it is not rewritten
+        let current = format!("# kept\n{}", upgraded);
+        fs::write(&path, &current).unwrap();
+        assert_eq!(migrate(&path, None).unwrap(), None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), current);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn signal_names() {
+        for valid in [
+            "SIGTERM",
+            "TERM",
+            "SIGRTMIN",
+            "SIGRTMIN+3",
+            "RTMAX-0",
+            "SIGRTMAX-30",
+        ] {
+            assert!(is_valid_signal(valid), "{}", valid);
+        }
+        for invalid in [
+            "HELLO",
+            "SIGHELLO",
+            "SIG",
+            "",
+            "sigterm",
+            "SIGRTMIN+",
+            "SIGRTMIN-1",
+            "RTMIN++1",
+            "SIGRTMAX+1",
+            "SIGRTMIN+31",
+        ] {
+            assert!(!is_valid_signal(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn render_unknown_variables() {
+        let src = "
+defs:
+  - template:
+      Unit: {}
+      Service: {ExecStart: \"/bin/app --port {{ prot }} --format '{{.State}}'\"}
+    instances:
+      - Unit: {Name: a, Description: App}
+        Vars: {port: '80'}
+";
+        let e = load_str(src, Path::new("test.yaml"), &Options::default())
+            .and_then(|d| render(&d, &Options::default(), &mut vec![]))
+            .unwrap_err();
+        assert!(matches!(e, Error::Validation(_)), "{}", e);
+        assert!(
+            e.to_string()
+                .contains("a: unknown variable `prot`, did you mean `port`?"),
+            "{}",
+            e
+        );
+
+        // placeholders that are not variable names are left as written
+        let units = render_str(&src.replace("prot", "port"), &Options::default());
+        assert_eq!(
+            values(&units["a.service"], "ExecStart"),
+            ["/bin/app --port 80 --format '{{.State}}'"]
+        );
+
+        let e = load_str(
+            &src.replace("Name: a,", "Name: 'a-{{ zone }}',"),
+            Path::new("test.yaml"),
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("unknown variable `zone`"), "{}", e);
+    }
+}
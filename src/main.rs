@@ -1,372 +1,244 @@
-use serde::{Deserialize, Serialize};
 use std::{
-    fs::{self, File},
-    io::BufReader,
+    collections::BTreeMap,
+    io::{self, Write},
     path::PathBuf,
 };
 
-use clap::Parser;
-
-fn default_template_deps() -> Vec<String> {
-    vec![]
-}
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "PascalCase")]
-struct TemplateUnit {
-    #[serde(default = "default_template_deps")]
-    pub requires: Vec<String>,
-    #[serde(default = "default_template_deps")]
-    pub after: Vec<String>,
-    #[serde(default = "default_template_deps")]
-    pub wants: Vec<String>
-}
-
-fn default_inherit_requires() -> bool {
-    true
-}
-
-fn default_inherit_after() -> bool {
-    true
-}
-
-fn default_inherit_wants() -> bool {
-    true
-}
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "PascalCase")]
-struct InstanceUnit {
-    pub name: String,
-    pub description: String,
-    pub requires: Option<Vec<String>>,
-    pub after: Option<Vec<String>>,
-    pub wants: Option<Vec<String>>,
-    #[serde(default = "default_inherit_requires")]
-    pub inherit_requires: bool,
-    #[serde(default = "default_inherit_after")]
-    pub inherit_after: bool,
-    #[serde(default = "default_inherit_wants")]
-    pub inherit_wants: bool,
-    pub requires_mounts_for: Option<Vec<String>>,
-}
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
-enum Restart {
-    No,
-    Always,
-    OnSuccess,
-    OnFailure,
-    OnWatchdog,
-    OnAbort,
-}
-
-impl core::fmt::Display for Restart {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match self {
-            Restart::No => "no",
-            Restart::Always => "always",
-            Restart::OnSuccess => "on-success",
-            Restart::OnFailure => "on-failure",
-            Restart::OnWatchdog => "on-watchdog",
-            Restart::OnAbort => "on-abort",
-        })
-        .unwrap();
-        Ok(())
-    }
-}
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-enum RemainAfterExit {
-    No,
-    Yes,
-}
-
-impl core::fmt::Display for RemainAfterExit {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match self {
-            RemainAfterExit::No => "no",
-            RemainAfterExit::Yes => "yes",
-        })
-        .unwrap();
-        Ok(())
-    }
-}
-
-fn default_remain_after_exit() -> Option<RemainAfterExit> {
-    Some(RemainAfterExit::No)
-}
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-enum ServiceType {
-    Simple,
-    OneShot,
-    Forking,
-    Notify,
-    DBus,
-    Idle,
-}
-
-impl core::fmt::Display for ServiceType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(
-            self.serialize(serde_yaml::value::Serializer)
-                .unwrap()
-                .as_str()
-                .unwrap(),
-        )
-        .unwrap();
-        Ok(())
-    }
-}
-
-fn default_service_type() -> Option<ServiceType> {
-    None
-}
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "PascalCase")]
-struct Service {
-    pub environment_file: Option<String>,
-    pub exec_start_pre: Option<String>,
-    pub exec_start: Option<String>,
-    pub exec_stop: Option<String>,
-    pub group: Option<String>,
-    #[serde(default = "default_remain_after_exit")]
-    pub remain_after_exit: Option<RemainAfterExit>,
-    pub restart: Option<Restart>,
-    pub timeout_start_sec: Option<u32>,
-    #[serde(default = "default_service_type", rename = "Type")]
-    pub service_type: Option<ServiceType>,
-    pub user: Option<String>,
-    pub working_directory: Option<String>,
-}
-
-fn default_wanted_by() -> String {
-    "default.target".into()
-}
+use clap::{Parser, Subcommand, ValueEnum};
+use gen_systemd_svcs::{
+    definitions_schema, generate, load, migrate, read_var_file, Error, Format, Options,
+    SCHEMA_VERSION,
+};
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "PascalCase")]
-pub struct Install {
-    #[serde(default = "default_wanted_by")]
-    pub wanted_by: String,
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatArg {
+    Yaml,
+    Json,
+    Toml,
 }
 
-pub fn default_install() -> Install {
-    Install {
-        wanted_by: default_wanted_by(),
+impl From<FormatArg> for Format {
+    fn from(value: FormatArg) -> Format {
+        match value {
+            FormatArg::Yaml => Format::Yaml,
+            FormatArg::Json => Format::Json,
+            FormatArg::Toml => Format::Toml,
+        }
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "PascalCase")]
-struct TemplateServiceDef {
-    pub unit: TemplateUnit,
-    pub service: Service,
-    #[serde(default = "default_install")]
-    pub install: Install,
-}
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "PascalCase")]
-struct InstanceServiceDef {
-    pub unit: InstanceUnit,
-    pub service: Option<Service>,
-    pub install: Option<Install>,
-}
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-struct TemplatesAndInstances {
-    pub template: TemplateServiceDef,
-    pub instances: Vec<InstanceServiceDef>,
-}
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-struct DefinitionFile {
-    pub defs: Vec<TemplatesAndInstances>,
-}
-
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    after_help = "Exit status: 1 for out of date files with --check, 65 for malformed \
+                  definitions, 66 for unreadable input, 73 for unwritable output and 78 for \
+                  definitions that cannot be generated."
+)]
 struct Cli {
-    #[arg(value_name = "FILE")]
-    definitions_file: PathBuf,
+    #[command(subcommand)]
+    subcommand: Option<Subcmd>,
+    #[arg(value_name = "FILE", required = true)]
+    definitions_file: Option<PathBuf>,
     #[arg(value_name = "OUTPUT_DIRECTORY")]
-    out_dst: PathBuf,
-}
-
-fn resolve_service_section(
-    instance_service: Option<Service>,
-    template_service: Service,
-    mut memo: String,
-) -> String {
-    let mut environment_file = template_service.environment_file;
-    let mut exec_start_pre = template_service.exec_start_pre;
-    let mut exec_start = template_service.exec_start;
-    let mut exec_stop = template_service.exec_stop;
-    let mut group = template_service.group;
-    let mut remain_after_exit = template_service.remain_after_exit;
-    let mut restart = template_service.restart;
-    let mut service_type = template_service.service_type;
-    let mut timeout_start_sec = template_service.timeout_start_sec;
-    let mut user = template_service.user;
-    let mut working_directory = template_service.working_directory;
-
-    if let Some(i) = instance_service {
-        if i.environment_file.is_some() {
-            environment_file = i.environment_file;
-        }
-        if i.exec_start_pre.is_some() {
-            exec_start_pre = i.exec_start_pre;
-        }
-        if i.exec_start.is_some() {
-            exec_start = i.exec_start;
-        }
-        if i.exec_stop.is_some() {
-            exec_stop = i.exec_stop;
-        }
-        if i.group.is_some() {
-            group = i.group;
-        }
-        if i.remain_after_exit.is_some() {
-            remain_after_exit = i.remain_after_exit;
-        }
-        if i.restart.is_some() {
-            restart = i.restart;
-        }
-        if i.service_type.is_some() {
-            service_type = i.service_type;
-        }
-        if i.timeout_start_sec.is_some() {
-            timeout_start_sec = i.timeout_start_sec;
-        }
-        if i.user.is_some() {
-            user = i.user;
+    out_dst: Option<PathBuf>,
+    /// Write each service as a name.service.d/override.conf drop-in
+    #[arg(long)]
+    dropin: bool,
+    /// Generate user units, defaulting the output directory to ~/.config/systemd/user
+    #[arg(long)]
+    user: bool,
+    /// Also write a 90-generated.preset file covering every generated unit
+    #[arg(long)]
+    preset: bool,
+    /// Also write sysusers.d fragments for the User= and Group= of each service
+    #[arg(long)]
+    sysusers: bool,
+    /// Treat warnings as errors
+    #[arg(long)]
+    strict: bool,
+    /// Print the files that would be written without writing them
+    #[arg(long)]
+    dry_run: bool,
+    /// Print a unified diff against the files in OUTPUT_DIRECTORY instead of writing them
+    #[arg(long)]
+    diff: bool,
+    /// List files in OUTPUT_DIRECTORY that differ from the generated ones and exit 1 if any do
+    #[arg(long)]
+    check: bool,
+    /// Set an interpolation variable, overriding Vars in the definitions
+    #[arg(long = "var", value_name = "KEY=VALUE")]
+    vars: Vec<String>,
+    /// Read interpolation variables from a YAML mapping, overridden by --var
+    #[arg(long, value_name = "FILE")]
+    var_file: Option<PathBuf>,
+    /// Apply each def's overrides for the named profile
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+    /// Format of FILE, detected from its extension by default
+    #[arg(long, value_enum)]
+    format: Option<FormatArg>,
+}
+
+#[derive(Subcommand)]
+enum Subcmd {
+    /// Upgrade FILE in place to the current schema version, without keeping comments
+    Migrate {
+        #[arg(value_name = "FILE")]
+        definitions_file: PathBuf,
+        /// Format of FILE, detected from its extension by default
+        #[arg(long, value_enum)]
+        format: Option<FormatArg>,
+    },
+    /// Print a JSON Schema for definitions files
+    Schema,
+}
+
+fn cli_vars(cli: &Cli) -> Result<BTreeMap<String, String>, Error> {
+    let mut vars = match &cli.var_file {
+        Some(path) => read_var_file(path)?,
+        None => BTreeMap::new(),
+    };
+    for var in &cli.vars {
+        match var.split_once('=') {
+            Some((k, v)) => vars.insert(k.into(), v.into()),
+            None => return Err(Error::Validation(format!("--var {} is not KEY=VALUE", var))),
+        };
+    }
+    Ok(vars)
+}
+
+/// Writes `out` to stdout. A reader that went away, as with `| head`, ends
+/// the run quietly instead of making println! panic.
+fn print(out: std::fmt::Arguments) -> Result<(), Error> {
+    let mut stdout = io::stdout().lock();
+    match stdout.write_fmt(out).and_then(|_| stdout.flush()) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => std::process::exit(0),
+        result => result.map_err(|e| Error::Write(format!("stdout: {}", e))),
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), Error> {
+    match &cli.subcommand {
+        Some(Subcmd::Migrate {
+            definitions_file,
+            format,
+        }) => {
+            match migrate(definitions_file, format.map(Format::from))? {
+                None => print(format_args!(
+                    "{} is already at version {}\n",
+                    definitions_file.display(),
+                    SCHEMA_VERSION
+                ))?,
+                Some(version) => print(format_args!(
+                    "Migrated {} from version {} to {}\n",
+                    definitions_file.display(),
+                    version,
+                    SCHEMA_VERSION
+                ))?,
+            }
+            return Ok(());
         }
-        if i.working_directory.is_some() {
-            working_directory = i.working_directory;
+        Some(Subcmd::Schema) => {
+            let schema = serde_json::to_string_pretty(&definitions_schema())
+                .map_err(|e| Error::Write(format!("schema: {}", e)))?;
+            return print(format_args!("{}\n", schema));
         }
-    }
-
-    memo += "\n[Service]\n";
-
-    if let Some(v) = environment_file {
-        memo += &format!("EnvironmentFile={}\n", v);
-    }
-    if let Some(v) = exec_start_pre {
-        memo += &format!("ExecStartPre={}\n", v);
-    }
-    if let Some(v) = exec_start {
-        memo += &format!("ExecStart={}\n", v);
-    }
-    if let Some(v) = exec_stop {
-        memo += &format!("ExecStop={}\n", v);
-    }
-    if let Some(v) = group {
-        memo += &format!("Group={}\n", v);
-    }
-    if let Some(v) = remain_after_exit {
-        memo += &format!("RemainAfterExit={}\n", v);
-    }
-    if let Some(v) = restart {
-        memo += &format!("Restart={}\n", v);
-    }
-    if let Some(v) = timeout_start_sec {
-        memo += &format!("TimeoutStartSec={}\n", v);
-    }
-    if let Some(v) = service_type {
-        memo += &format!("Type={}\n", v);
-    }
-    if let Some(v) = user {
-        memo += &format!("User={}\n", v);
-    }
-    if let Some(v) = working_directory {
-        memo += &format!("WorkingDirectory={}\n", v);
-    }
-
-    memo
-}
-
-fn resolve(instance: InstanceServiceDef, template: TemplateServiceDef) -> String {
-    let mut memo = String::from("; THIS FILE IS GENERATED BY gen-systemd-svc\n");
-    memo += "; DO NOT EDIT THIS FILE DIRECTLY!\n";
-    memo += "\n[Unit]\n";
-    memo += &format!("Description={}\n", instance.unit.description);
-
-    let requires: Vec<String> = match instance.unit.inherit_requires {
-        true => {
-            let mut v = template.unit.requires.clone();
-            v.extend(instance.unit.requires.unwrap_or_default());
-            v
-        }
-        false => instance.unit.requires.unwrap_or_default(),
+        None => {}
+    }
+    // FILE is required unless a subcommand is given
+    let definitions_file = cli
+        .definitions_file
+        .as_deref()
+        .ok_or_else(|| Error::Validation("FILE is required".to_string()))?;
+    let options = Options {
+        format: cli.format.map(Format::from),
+        profile: cli.profile.clone(),
+        vars: cli_vars(cli)?,
+        drop_in: cli.dropin,
+        user: cli.user,
+        preset: cli.preset,
+        sysusers: cli.sysusers,
+        strict: cli.strict,
+        dry_run: cli.dry_run,
+        diff: cli.diff,
+        check: cli.check,
     };
-
-    for req in requires {
-        memo += &format!("Requires={}\n", req);
-    }
-
-    let afters: Vec<String> = match instance.unit.inherit_after {
-        true => {
-            let mut v = template.unit.after.clone();
-            v.extend(instance.unit.after.unwrap_or_default());
-            v
+    let def_file = load(definitions_file, &options)?;
+    let out_dst = match (&cli.out_dst, cli.user) {
+        (Some(v), _) => v.clone(),
+        (None, true) => match std::env::var("HOME") {
+            Ok(home) => PathBuf::from(home).join(".config/systemd/user"),
+            Err(_) => {
+                return Err(Error::Validation(
+                    "HOME is not set, give an OUTPUT_DIRECTORY".to_string(),
+                ))
+            }
+        },
+        (None, false) => {
+            return Err(Error::Validation(
+                "OUTPUT_DIRECTORY is required unless --user is given".to_string(),
+            ))
         }
-        false => instance.unit.after.unwrap_or_default(),
     };
-
-    for after in afters {
-        memo += &format!("After={}\n", after);
+    let mut warnings = vec![];
+    let generated = generate(&def_file, &out_dst, &options, &mut warnings);
+    for warning in &warnings {
+        match options.strict {
+            true => eprintln!("Error: {}", warning),
+            false => eprintln!("Warning: {}", warning),
+        }
     }
+    let generated = generated?;
 
-    let wants: Vec<String> = match instance.unit.inherit_wants {
-        true => {
-            let mut v = template.unit.wants.clone();
-            v.extend(instance.unit.wants.unwrap_or_default());
-            v
+    let write = !cli.dry_run && !cli.diff && !cli.check;
+    for file in &generated.files {
+        match (write, cli.dry_run) {
+            (true, _) => print(format_args!("Writing {:?}\n", file))?,
+            (false, true) => print(format_args!("Would write {:?}\n", file))?,
+            (false, false) => {}
         }
-        false => instance.unit.wants.unwrap_or_default(),
-    };
-
-    for want in wants {
-        memo += &format!("Wants={}\n", want);
     }
-
-    if let Some(v) = instance.unit.requires_mounts_for {
-        memo += &format!("RequiresMountsFor={}\n", v.join(" "));
+    for diff in &generated.diffs {
+        print(format_args!("{}", diff))?;
+    }
+    for file in &generated.outdated {
+        print(format_args!("{}\n", file))?;
+    }
+    if !generated.outdated.is_empty() {
+        return Err(Error::Outdated(format!(
+            "files out of date in {}: {}",
+            out_dst.display(),
+            generated.outdated.len()
+        )));
+    }
+    if cli.diff || cli.check {
+        return Ok(());
+    }
+    for def in &def_file.defs {
+        if let Some(native) = &def.native_template {
+            let services: Vec<String> = def
+                .instances
+                .iter()
+                .map(|i| format!("{}@{}.service", native, i.unit.name))
+                .collect();
+            print(format_args!(
+                "Enable instances with: systemctl enable {}\n",
+                services.join(" ")
+            ))?;
+        }
     }
-
-    // SERVICE PART
-    let mut memo = resolve_service_section(instance.service, template.service, memo);
-
-    // INSTALL PART
-    memo += "\n[Install]\n";
-    memo += &format!(
-        "WantedBy={}\n",
-        instance.install.unwrap_or(template.install).wanted_by
-    );
-    memo
+    Ok(())
 }
 
 fn main() {
     let cli = Cli::parse();
-    let file = File::open(cli.definitions_file.as_path()).unwrap();
-    let reader = BufReader::new(file);
-    let def_file: DefinitionFile = serde_yaml::from_reader(reader).unwrap();
-
-    for def in def_file.defs {
-        for instance in def.instances {
-            let name = instance.unit.name.clone();
-            println!("Generating definition for {}", name);
-            let filename = format!("{}.service", name);
-            let resolved = resolve(instance, def.template.clone());
-            let dst = cli.out_dst.join(filename);
-            println!("Writing {:?}", dst);
-            fs::write(dst, resolved).expect("Unable to write file")
-        }
+    if let Err(e) = run(&cli) {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
     }
 }
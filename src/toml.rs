@@ -0,0 +1,696 @@
+//! Reads and writes the TOML form of definitions files.
+
+use crate::scalar_string;
+
+/// Just enough TOML to read a definitions file: tables, arrays of tables,
+/// dotted keys, inline tables, arrays, strings, integers, floats and booleans.
+/// Dates and times are kept as strings.
+#[derive(Default)]
+struct TomlParser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Where the value being parsed is, from the document root.
+    path: Vec<serde_yaml::Value>,
+    /// Line and column of every table and value, by path.
+    locations: Vec<(Vec<serde_yaml::Value>, (usize, usize))>,
+    /// Tables that a header, a dotted key or an inline table has defined.
+    defined: Vec<Vec<serde_yaml::Value>>,
+    /// Arrays that `[[...]]` headers made.
+    table_arrays: Vec<Vec<serde_yaml::Value>>,
+}
+
+impl TomlParser {
+    fn position(&self) -> (usize, usize) {
+        let before = &self.chars[..self.pos];
+        let line_start = before.iter().rposition(|&c| c == '\n').map_or(0, |i| i + 1);
+        (
+            before.iter().filter(|&&c| c == '\n').count() + 1,
+            self.pos - line_start + 1,
+        )
+    }
+
+    fn error(&self, msg: &str) -> String {
+        format!("line {}: {}", self.position().0, msg)
+    }
+
+    /// Records that whatever is at `path` starts here.
+    fn located(&mut self, path: Vec<serde_yaml::Value>) {
+        let position = self.position();
+        self.locations.push((path, position));
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_str(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected '{}'", c)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Skips whitespace, comments and newlines.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_ws();
+            self.skip_comment();
+            if self.peek() == Some('\n') {
+                self.pos += 1;
+            } else if self.peek_str("\r\n") {
+                self.pos += 2;
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_ws();
+        self.skip_comment();
+        if self.peek().is_none() || self.peek() == Some('\n') || self.peek_str("\r\n") {
+            Ok(())
+        } else {
+            Err(self.error("expected a newline"))
+        }
+    }
+
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut path = vec![];
+        loop {
+            self.skip_ws();
+            if matches!(self.peek(), Some('"' | '\'')) {
+                path.push(self.string()?);
+            } else {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    self.pos += 1;
+                }
+                if start == self.pos {
+                    return Err(self.error("expected a key"));
+                }
+                path.push(self.chars[start..self.pos].iter().collect());
+            }
+            self.skip_ws();
+            if self.peek() != Some('.') {
+                return Ok(path);
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn key_value(&mut self, root: &mut serde_yaml::Value, table: &[String]) -> Result<(), String> {
+        let key = self.key()?;
+        self.expect('=')?;
+        self.skip_ws();
+        let (last, parent) = key.split_last().unwrap();
+        let keys = [table, parent].concat();
+        let depth = self.path.len();
+        self.path.extend(toml_path(root, &keys));
+        self.path.push(last.as_str().into());
+        self.located(self.path.clone());
+        let value = self.value();
+        let path = self.path.split_off(depth);
+        let value = value?;
+        if value.is_mapping() {
+            self.defined.push([&self.path[..], &path].concat());
+        }
+        let map = toml_table(root, &keys).map_err(|e| self.error(&e))?;
+        if map.insert(last.as_str().into(), value).is_some() {
+            return Err(self.error(&format!("duplicate key {}", last)));
+        }
+        // dotted keys define each table they go through
+        for i in 1..=parent.len() {
+            let path = [&self.path[..], &toml_path(root, &keys[..table.len() + i])].concat();
+            self.defined.push(path);
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let quote = self.peek().unwrap();
+        let delimiter = quote.to_string().repeat(3);
+        let multiline = self.peek_str(&delimiter);
+        if multiline {
+            self.pos += 3;
+            // a newline straight after the opening delimiter is trimmed
+            if self.peek() == Some('\n') {
+                self.pos += 1;
+            } else if self.peek_str("\r\n") {
+                self.pos += 2;
+            }
+        } else {
+            self.pos += 1;
+        }
+
+        let mut s = String::new();
+        loop {
+            let c = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            if c == quote && !multiline {
+                self.pos += 1;
+                return Ok(s);
+            }
+            if c == quote && self.peek_str(&delimiter) {
+                self.pos += 3;
+                // up to two more quotes still belong to the string
+                for _ in 0..2 {
+                    if self.peek() == Some(quote) {
+                        s.push(quote);
+                        self.pos += 1;
+                    }
+                }
+                return Ok(s);
+            }
+            if c == '\n' && !multiline {
+                return Err(self.error("unterminated string"));
+            }
+            self.pos += 1;
+            if c != '\\' || quote == '\'' {
+                s.push(c);
+                continue;
+            }
+            let escape = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match escape {
+                'b' => s.push('\u{8}'),
+                't' => s.push('\t'),
+                'n' => s.push('\n'),
+                'f' => s.push('\u{c}'),
+                'r' => s.push('\r'),
+                '"' => s.push('"'),
+                '\\' => s.push('\\'),
+                'u' | 'U' => {
+                    let len = if escape == 'u' { 4 } else { 8 };
+                    let hex: String = self
+                        .chars
+                        .get(self.pos..self.pos + len)
+                        .unwrap_or_default()
+                        .iter()
+                        .collect();
+                    let ch = hex
+                        .chars()
+                        .all(|h| h.is_ascii_hexdigit())
+                        .then(|| u32::from_str_radix(&hex, 16).ok())
+                        .flatten()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| {
+                            self.error(&format!("invalid escape \\{}{}", escape, hex))
+                        })?;
+                    s.push(ch);
+                    self.pos += len;
+                }
+                // a backslash at the end of a line trims the following whitespace
+                ' ' | '\t' | '\r' | '\n' if multiline => {
+                    while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => return Err(self.error(&format!("invalid escape \\{}", escape))),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<serde_yaml::Value, String> {
+        match self.peek() {
+            Some('"' | '\'') => Ok(self.string()?.into()),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = vec![];
+                loop {
+                    self.skip_blank();
+                    if self.peek() == Some(']') {
+                        self.pos += 1;
+                        return Ok(serde_yaml::Value::Sequence(items));
+                    }
+                    self.path.push(items.len().into());
+                    self.located(self.path.clone());
+                    let item = self.value();
+                    self.path.pop();
+                    items.push(item?);
+                    self.skip_blank();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(']') => {}
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut table = serde_yaml::Mapping::new().into();
+                self.skip_ws();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(table);
+                }
+                loop {
+                    self.key_value(&mut table, &[])?;
+                    self.skip_ws();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some('}') => {
+                            self.pos += 1;
+                            return Ok(table);
+                        }
+                        _ => return Err(self.error("expected ',' or '}'")),
+                    }
+                }
+            }
+            _ => self.scalar(),
+        }
+    }
+
+    fn scalar(&mut self) -> Result<serde_yaml::Value, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || "+-_.:".contains(c)) {
+            self.pos += 1;
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+        let invalid = || self.error(&format!("invalid value {:?}", token));
+        // dates and times are left for the field's own deserializer
+        let is_datetime =
+            token.contains(':') || (token.len() >= 10 && token.get(4..5) == Some("-"));
+        let value = match token.as_str() {
+            "" => return Err(self.error("expected a value")),
+            "true" => true.into(),
+            "false" => false.into(),
+            "inf" | "+inf" => f64::INFINITY.into(),
+            "-inf" => f64::NEG_INFINITY.into(),
+            "nan" | "+nan" | "-nan" => f64::NAN.into(),
+            _ if is_datetime => token.clone().into(),
+            _ => {
+                let digits = token.replace('_', "");
+                let int = if let Some(hex) = digits.strip_prefix("0x") {
+                    i64::from_str_radix(hex, 16).ok()
+                } else if let Some(oct) = digits.strip_prefix("0o") {
+                    i64::from_str_radix(oct, 8).ok()
+                } else if let Some(bin) = digits.strip_prefix("0b") {
+                    i64::from_str_radix(bin, 2).ok()
+                } else {
+                    digits.parse::<i64>().ok()
+                };
+                match int {
+                    Some(i) => i.into(),
+                    None if digits.chars().any(|c| c.is_ascii_digit()) => {
+                        digits.parse::<f64>().map_err(|_| invalid())?.into()
+                    }
+                    None => return Err(invalid()),
+                }
+            }
+        };
+        Ok(value)
+    }
+}
+
+/// `keys` as a path from `root`, going through the last element of each
+/// array of tables.
+fn toml_path(root: &serde_yaml::Value, keys: &[String]) -> Vec<serde_yaml::Value> {
+    let mut path = vec![];
+    let mut value = Some(root);
+    for key in keys {
+        path.push(key.as_str().into());
+        value = value.and_then(|v| v.get(key.as_str()));
+        if let Some(serde_yaml::Value::Sequence(s)) = value {
+            if let Some(last) = s.last() {
+                path.push((s.len() - 1).into());
+                value = Some(last);
+            }
+        }
+    }
+    path
+}
+
+/// Finds the table at `path`, creating it if needed. Arrays of tables resolve
+/// to their last element.
+fn toml_table<'a>(
+    root: &'a mut serde_yaml::Value,
+    path: &[String],
+) -> Result<&'a mut serde_yaml::Mapping, String> {
+    let mut table = root.as_mapping_mut().ok_or("the document is not a table")?;
+    for key in path {
+        let value = table
+            .entry(key.as_str().into())
+            .or_insert_with(|| serde_yaml::Mapping::new().into());
+        let value = match value {
+            serde_yaml::Value::Sequence(s) => s.last_mut(),
+            v => Some(v),
+        };
+        table = value
+            .and_then(|v| v.as_mapping_mut())
+            .ok_or_else(|| format!("{} is not a table", key))?;
+    }
+    Ok(table)
+}
+
+pub(crate) fn parse_toml(src: &str) -> Result<serde_yaml::Value, String> {
+    parse_toml_located(src).map(|(value, _)| value)
+}
+
+/// Parses `src` along with the line and column of every table and value in
+/// it.
+#[allow(clippy::type_complexity)]
+pub(crate) fn parse_toml_located(
+    src: &str,
+) -> Result<
+    (
+        serde_yaml::Value,
+        Vec<(Vec<serde_yaml::Value>, (usize, usize))>,
+    ),
+    String,
+> {
+    let mut parser = TomlParser {
+        chars: src.chars().collect(),
+        ..Default::default()
+    };
+    let mut root = serde_yaml::Mapping::new().into();
+    let mut table = vec![];
+    loop {
+        parser.skip_blank();
+        let start = parser.pos;
+        match parser.peek() {
+            None => return Ok((root, parser.locations)),
+            Some('[') => {
+                parser.pos += 1;
+                let array = parser.peek() == Some('[');
+                if array {
+                    parser.pos += 1;
+                }
+                table = parser.key()?;
+                parser.expect(']')?;
+                if array {
+                    parser.expect(']')?;
+                    let (last, parent) = table.split_last().unwrap();
+                    let path = [toml_path(&root, parent), vec![last.as_str().into()]].concat();
+                    let parent = toml_table(&mut root, parent).map_err(|e| parser.error(&e))?;
+                    // only an array this kind of header made can take more tables
+                    let made =
+                        !parent.contains_key(last.as_str()) || parser.table_arrays.contains(&path);
+                    match parent
+                        .entry(last.as_str().into())
+                        .or_insert_with(|| serde_yaml::Value::Sequence(vec![]))
+                    {
+                        serde_yaml::Value::Sequence(s) if made => {
+                            s.push(serde_yaml::Mapping::new().into())
+                        }
+                        _ => {
+                            return Err(parser.error(&format!("{} is not an array of tables", last)))
+                        }
+                    }
+                    parser.table_arrays.push(path);
+                } else {
+                    let path = toml_path(&root, &table);
+                    if let Some(serde_yaml::Value::Number(_)) = path.last() {
+                        return Err(
+                            parser.error(&format!("{} is an array of tables", table.join(".")))
+                        );
+                    }
+                    if parser.defined.contains(&path) {
+                        return Err(parser.error(&format!("duplicate table {}", table.join("."))));
+                    }
+                    parser.defined.push(path);
+                    toml_table(&mut root, &table).map_err(|e| parser.error(&e))?;
+                }
+                let end = parser.pos;
+                parser.pos = start;
+                parser.located(toml_path(&root, &table));
+                parser.pos = end;
+            }
+            Some(_) => parser.key_value(&mut root, &table)?,
+        }
+        parser.end_of_line()?;
+    }
+}
+
+fn toml_key(key: &serde_yaml::Value) -> Result<String, String> {
+    let key = scalar_string(key).ok_or("keys must be strings")?;
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        Ok(key)
+    } else {
+        serde_json::to_string(&key).map_err(|e| e.to_string())
+    }
+}
+
+fn toml_value(value: &serde_yaml::Value) -> Result<String, String> {
+    Ok(match value {
+        serde_yaml::Value::Null => return Err("TOML has no null".to_string()),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        serde_yaml::Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() && f.is_nan() => "nan".to_string(),
+            Some(f) if n.is_f64() && f.is_infinite() => {
+                if f > 0.0 { "inf" } else { "-inf" }.to_string()
+            }
+            Some(f) if n.is_f64() => format!("{:?}", f),
+            _ => n.to_string(),
+        },
+        serde_yaml::Value::String(s) => serde_json::to_string(s).map_err(|e| e.to_string())?,
+        serde_yaml::Value::Sequence(s) => format!(
+            "[{}]",
+            s.iter()
+                .map(toml_value)
+                .collect::<Result<Vec<_>, _>>()?
+                .join(", ")
+        ),
+        serde_yaml::Value::Mapping(m) => format!(
+            "{{ {} }}",
+            m.iter()
+                .map(|(k, v)| Ok(format!("{} = {}", toml_key(k)?, toml_value(v)?)))
+                .collect::<Result<Vec<_>, String>>()?
+                .join(", ")
+        ),
+        serde_yaml::Value::Tagged(_) => return Err("TOML has no tags".to_string()),
+    })
+}
+
+fn is_table_array(value: &serde_yaml::Value) -> bool {
+    matches!(value, serde_yaml::Value::Sequence(s) if !s.is_empty() && s.iter().all(serde_yaml::Value::is_mapping))
+}
+
+/// Writes `table` under the `[path]` header, giving every nested table and
+/// array of tables its own header.
+fn toml_table_contents(
+    out: &mut String,
+    path: &[String],
+    table: &serde_yaml::Mapping,
+) -> Result<(), String> {
+    // plain keys have to come before any sub-table headers
+    for (k, v) in table {
+        if !v.is_mapping() && !is_table_array(v) {
+            *out += &format!("{} = {}\n", toml_key(k)?, toml_value(v)?);
+        }
+    }
+    for (k, v) in table {
+        let path = [path, &[toml_key(k)?]].concat();
+        match v {
+            serde_yaml::Value::Mapping(m) => {
+                *out += &format!("\n[{}]\n", path.join("."));
+                toml_table_contents(out, &path, m)?;
+            }
+            serde_yaml::Value::Sequence(s) if is_table_array(v) => {
+                for t in s {
+                    *out += &format!("\n[[{}]]\n", path.join("."));
+                    let t = t.as_mapping().ok_or("expected a table")?;
+                    toml_table_contents(out, &path, t)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn to_toml(value: &serde_yaml::Value) -> Result<String, String> {
+    let mut out = String::new();
+    toml_table_contents(
+        &mut out,
+        &[],
+        value.as_mapping().ok_or("expected a mapping")?,
+    )?;
+    Ok(out.trim_start().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(src: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(src).unwrap()
+    }
+
+    #[test]
+    fn toml_tables() {
+        let value =
+            parse_toml("a = 1\n\n[b]\nc = true\n\n[b.d]\ne = 'x'\n\n[f.g]\nh = 1.5\n").unwrap();
+        assert_eq!(
+            value,
+            yaml("{a: 1, b: {c: true, d: {e: x}}, f: {g: {h: 1.5}}}")
+        );
+    }
+
+    #[test]
+    fn toml_dotted_keys() {
+        let value = parse_toml("a.b = 1\na.c = 2\n\"d.e\".f = 3\n").unwrap();
+        assert_eq!(value, yaml("{a: {b: 1, c: 2}, d.e: {f: 3}}"));
+    }
+
+    #[test]
+    fn toml_arrays_of_tables() {
+        let src = "[[defs]]\nname = 'a'\n[defs.unit]\nx = 1\n\n[[defs]]\nname = 'b'\n[[defs.instances]]\ny = 2\n";
+        assert_eq!(
+            parse_toml(src).unwrap(),
+            yaml("{defs: [{name: a, unit: {x: 1}}, {name: b, instances: [{y: 2}]}]}")
+        );
+    }
+
+    #[test]
+    fn toml_inline_tables_and_arrays() {
+        let src = "a = { b = 1, c.d = 'x', e = {} }\nf = [1, [2, 3], { g = 4 }]\nh = [\n  'x', # comment\n  'y',\n]\n";
+        assert_eq!(
+            parse_toml(src).unwrap(),
+            yaml("{a: {b: 1, c: {d: x}, e: {}}, f: [1, [2, 3], {g: 4}], h: [x, y]}")
+        );
+    }
+
+    #[test]
+    fn toml_strings() {
+        let src = r#"a = "tab\there \"q\" \\ \u00e9 \U0001F600"
+b = 'C:\path\n'
+c = """
+one
+two \
+    three"""
+d = '''
+raw \n'''
+e = """""quoted"""""
+"#;
+        let value = parse_toml(src).unwrap();
+        assert_eq!(
+            value["a"],
+            yaml("\"tab\\there \\\"q\\\" \\\\ \u{e9} \u{1F600}\"")
+        );
+        assert_eq!(value["b"], yaml(r"'C:\path\n'"));
+        assert_eq!(value["c"], yaml("\"one\\ntwo three\""));
+        assert_eq!(value["d"], yaml(r"'raw \n'"));
+        assert_eq!(value["e"], yaml(r#"'""quoted""'"#));
+    }
+
+    #[test]
+    fn toml_scalars() {
+        let src = "a = -1_000\nb = 0xff\nc = 0o17\nd = 0b101\ne = 6.02e23\nf = inf\ng = false\nh = 1979-05-27T07:32:00Z\n";
+        let value = parse_toml(src).unwrap();
+        assert_eq!(value["a"], yaml("-1000"));
+        assert_eq!(value["b"], yaml("255"));
+        assert_eq!(value["c"], yaml("15"));
+        assert_eq!(value["d"], yaml("5"));
+        assert_eq!(value["e"], yaml("6.02e23"));
+        assert_eq!(value["f"], serde_yaml::Value::from(f64::INFINITY));
+        assert_eq!(value["g"], yaml("false"));
+        assert_eq!(value["h"], yaml("'1979-05-27T07:32:00Z'"));
+    }
+
+    #[test]
+    fn toml_malformed() {
+        for (src, error) in [
+            ("a = 1\na = 2\n", "line 2: duplicate key a"),
+            ("[a]\n[a]\n", "line 2: duplicate table a"),
+            ("[a]\nb = 1\n[c]\n[a]\n", "line 4: duplicate table a"),
+            ("a.b = 1\n[a]\n", "line 2: duplicate table a"),
+            ("a = { b = 1 }\n[a]\n", "line 2: duplicate table a"),
+            ("[[a]]\n[a]\n", "line 2: a is an array of tables"),
+            ("a = []\n[[a]]\n", "line 2: a is not an array of tables"),
+            ("a = 1\n[a.b]\n", "line 2: a is not a table"),
+            ("a = \"x\n", "line 1: unterminated string"),
+            ("a = \"\\x\"\n", "line 1: invalid escape \\x"),
+            ("a = \"\\u12xy\"\n", "line 1: invalid escape \\u12xy"),
+            ("a = [1 2]\n", "line 1: expected ',' or ']'"),
+            ("a = { b = 1\n", "line 1: expected ',' or '}'"),
+            ("a = 1 b = 2\n", "line 1: expected a newline"),
+            ("a =\n", "line 1: expected a value"),
+            ("a = yes\n", "line 1: invalid value \"yes\""),
+            ("= 1\n", "line 1: expected a key"),
+            ("[a\n", "line 1: expected ']'"),
+        ] {
+            assert_eq!(parse_toml(src).unwrap_err(), error, "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn toml_sub_tables_after_their_parent() {
+        // a table implied by an earlier header can still be defined once
+        assert!(parse_toml("[a.b]\n[a]\nc = 1\n").is_ok());
+        assert!(parse_toml("[a]\nb.c = 1\n[a.b.d]\n").is_ok());
+        assert!(parse_toml("[[a]]\n[[a]]\n[a.b]\n").is_ok());
+    }
+
+    fn round_trip(src: &str) {
+        let value = yaml(src);
+        let toml = to_toml(&value).unwrap();
+        assert_eq!(parse_toml(&toml).unwrap(), value, "{}", toml);
+    }
+
+    #[test]
+    fn toml_round_trip_tables() {
+        round_trip("{a: 1, b: {c: true, d: {e: x}}, 'f g': {h: 1.5, i: -2}}");
+    }
+
+    #[test]
+    fn toml_round_trip_arrays_of_tables() {
+        round_trip(
+            "
+version: 1
+defs:
+  - template:
+      Unit: {After: [a.service]}
+      Service: {ExecStart: [{Cmd: /bin/x, Args: ['a b']}, /bin/y]}
+    instances:
+      - Unit: {Name: a}
+        Matrix: {n: [1, 2]}
+      - Unit: {Name: b}
+  - template: {Unit: {}}
+    instances: []
+",
+        );
+    }
+
+    #[test]
+    fn toml_round_trip_inline_tables() {
+        // tables inside plain arrays have to stay inline
+        round_trip("{a: [1, {b: 2, c: {d: [3]}}], e: [[{f: g}]], h: {}}");
+    }
+
+    #[test]
+    fn toml_round_trip_strings() {
+        round_trip(
+            r#"{a: "quote \" backslash \\ tab \t newline \n é", "b.c": "%i $MAINPID", "": x, d: '1979-05-27'}"#,
+        );
+    }
+}